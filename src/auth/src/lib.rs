@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::error::Error;
+use axum::extract::{FromRef, FromRequest, Json, Request};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use structure::{CardInfo, Credentials, FieldError};
+use storage::SharedStorage;
+use function::{verify_token, KeyRing, SharedKeys, TokenUse};
+
+// JSON body whose card_holder / target / token have been checked against the stored connections
+pub struct Verified<T>(pub T);
+
+impl<S, T> FromRequest<S> for Verified<T>
+where
+    T: DeserializeOwned + Credentials,
+    S: Send + Sync,
+    SharedStorage: FromRef<S>,
+    SharedKeys: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(body) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(reject_body)?;
+
+        let storage = SharedStorage::from_ref(state);
+        let card_map = storage.load_accounts_of(&[body.card_holder()]).map_err(|e| {
+            eprintln!("Error： {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response()
+        })?;
+
+        let keys = SharedKeys::from_ref(state);
+        let keys = keys.read().unwrap_or_else(|e| e.into_inner());
+        get_verified_card(&card_map, &keys, body.card_holder(), body.target(), body.token())
+            .map_err(IntoResponse::into_response)?;
+
+        Ok(Verified(body))
+    }
+}
+
+// a field that doesn't parse, like an unknown buy_type or a zero leverage, gets a 422 naming it,
+// anything else keeps axum's own rejection
+fn reject_body(rejection: JsonRejection) -> Response {
+    if let JsonRejection::JsonDataError(e) = &rejection {
+        let path_error = e.source()
+            .and_then(|e| e.source())
+            .and_then(|e| e.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>());
+        if let Some(err) = path_error {
+            // serde_json ends its message with the position in the body, which says nothing more than the path
+            let message = err.inner().to_string();
+            let message = message.rsplit_once(" at line ").map_or(message.as_str(), |(m, _)| m);
+            let field = FieldError::new(err.path().to_string(), message);
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json(field)).into_response();
+        }
+    }
+    rejection.into_response()
+}
+
+pub fn get_verified_card<'a>(
+    card_map: &'a HashMap<u64, CardInfo>,
+    keys: &KeyRing,
+    card_holder: &str,
+    target: &str,
+    token: &str,
+) -> Result<&'a CardInfo, (StatusCode, String)> {
+    let data = get_connected_card(card_map, card_holder, target, token)?;
+    verify_token(keys, token, data, TokenUse::Access).map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+    Ok(data)
+}
+
+// only checks that the token is the one stored for this platform, not its signature or age
+pub fn get_connected_card<'a>(
+    card_map: &'a HashMap<u64, CardInfo>,
+    card_holder: &str,
+    target: &str,
+    token: &str,
+) -> Result<&'a CardInfo, (StatusCode, String)> {
+    let unauthorized = |msg: &str| (StatusCode::UNAUTHORIZED, msg.to_string());
+
+    let data = card_map.values().find(|data| data.card_holder == card_holder)
+        .ok_or_else(|| unauthorized("No card holder found"))?;
+
+    let connection_map = data.connection.as_ref()
+        .ok_or_else(|| unauthorized("This card is not connected to any platform"))?;
+
+    let stored_token_vec = connection_map.get(target)
+        .ok_or_else(|| unauthorized("No platform connection record found"))?;
+
+    let matched = stored_token_vec.iter().any(|t| t.target == target && t.token == token);
+    if !matched {
+        return Err(unauthorized("Failed to verify"));
+    }
+    Ok(data)
+}
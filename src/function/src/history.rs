@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::FromPrimitive;
+use structure::{FieldError, TradeAction, TradeEntry, TradeHistory, TradeHistoryQuery, TradeIndex, TradePage, TradeRef, TradeSort, TransactionType};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+// filters that can never match are refused, so a mistake doesn't look like an empty history
+pub fn check_history_query(query: &TradeHistoryQuery) -> Result<(), FieldError> {
+    if let (Some(from), Some(to)) = (query.from, query.to)
+        && from >= to
+    {
+        return Err(FieldError::new("to", format!("to must be after from ({})", from)));
+    }
+    if let (Some(min), Some(max)) = (query.min_amount, query.max_amount)
+        && min > max
+    {
+        return Err(FieldError::new("max_amount", format!("max_amount must be at least min_amount ({})", min)));
+    }
+    Ok(())
+}
+
+fn matches(trade: &TradeHistory, query: &TradeHistoryQuery) -> bool {
+    let (action, amount) = match trade.transaction_type {
+        TransactionType::Credit { amount } => (TradeAction::Credit, amount),
+        TransactionType::Debit { amount } => (TradeAction::Debit, amount),
+    };
+    let amount = Decimal::from_f64(amount).unwrap_or_default();
+
+    query.action.is_none_or(|wanted| wanted == action)
+        && query.counterparty.as_ref().is_none_or(|user| user == &trade.target_user)
+        && query.min_amount.is_none_or(|min| amount >= min)
+        && query.max_amount.is_none_or(|max| amount <= max)
+}
+
+// one page of the card's trades in index order; the cursor is the `seq` of the last trade
+// handed out, so trades made while paging never shift or repeat a page
+pub fn trade_page(index: Option<&TradeIndex>, trade_map: &HashMap<i64, TradeHistory>, query: &TradeHistoryQuery) -> TradePage {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut refs: Vec<&TradeRef> = index.map(|index| index.iter().collect()).unwrap_or_default();
+    if query.sort == TradeSort::Newest {
+        refs.reverse();
+    }
+
+    let mut trades: Vec<TradeEntry> = refs.into_iter()
+        .filter(|r| match (query.cursor, query.sort) {
+            (Some(cursor), TradeSort::Newest) => r.seq < cursor,
+            (Some(cursor), TradeSort::Oldest) => r.seq > cursor,
+            (None, _) => true,
+        })
+        .filter(|r| query.from.is_none_or(|from| r.timestamp >= from) && query.to.is_none_or(|to| r.timestamp < to))
+        .filter_map(|r| {
+            let trade = trade_map.get(&r.trade_id).filter(|trade| matches(trade, query))?;
+            Some(TradeEntry { id: r.trade_id, seq: r.seq, trade: trade.clone() })
+        })
+        .take(limit + 1)
+        .collect();
+
+    let mut next_cursor = None;
+    if trades.len() > limit {
+        trades.truncate(limit);
+        next_cursor = trades.last().map(|entry| entry.seq);
+    }
+    TradePage { trades, next_cursor }
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the persisted id sequences, every id is taken from one of them
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sequence {
+    Trade,
+    Order,
+    LedgerEntry,
+}
+
+impl Sequence {
+    pub const ALL: [Sequence; 3] = [Sequence::Trade, Sequence::Order, Sequence::LedgerEntry];
+
+    // key in sequences.json and the sequences table
+    pub fn name(self) -> &'static str {
+        match self {
+            Sequence::Trade => "trade",
+            Sequence::Order => "order",
+            Sequence::LedgerEntry => "ledger",
+        }
+    }
+}
+
+// where every crate takes new ids from, ids only ever go up and are never handed out twice
+pub trait IdSource {
+    fn next_id(&self, sequence: Sequence) -> Result<i64, String>;
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum IdFormat {
+    // 1, 2, 3, ...
+    #[default]
+    Sequential,
+    // unix milliseconds shifted left by 10 bits plus a counter, like the time part of a ULID;
+    // ids sort by creation time and stay below 2^53 so JavaScript clients read them exactly
+    Sortable,
+}
+
+impl IdFormat {
+    // TRADE_IDS=sequential (default) or sortable, order and ledger entry ids are always sequential
+    pub fn from_env() -> Result<Self, String> {
+        let format = env::var("TRADE_IDS").unwrap_or_else(|_| String::from("sequential"));
+        match format.to_lowercase().as_str() {
+            "sequential" => Ok(IdFormat::Sequential),
+            "sortable" => Ok(IdFormat::Sortable),
+            other => Err(format!("Unknown trade id format：{}", other)),
+        }
+    }
+
+    // the id after `last`, a burst of more than 1024 ids in one millisecond borrows from the next one
+    pub fn next_after(self, last: i64) -> i64 {
+        match self {
+            IdFormat::Sequential => last + 1,
+            IdFormat::Sortable => {
+                let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+                (last + 1).max(millis << 10)
+            }
+        }
+    }
+
+    // how far past the latest id a reservation reaches, so storage is written once per block
+    pub fn block(self) -> i64 {
+        match self {
+            IdFormat::Sequential => 100,
+            // about a minute of ids
+            IdFormat::Sortable => 60_000 << 10,
+        }
+    }
+}
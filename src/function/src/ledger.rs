@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use structure::{CardInfo, LedgerEntry, Posting};
+use crate::ids::{IdSource, Sequence};
+
+// the bank's side of deposits and withdrawals, it goes negative as money is handed out
+pub const TREASURY: &str = "bank:treasury";
+// principal of open stock positions and reserved buy orders, "Stock! Bot" in trade history
+pub const STOCK_CLEARING: &str = "bank:stock_clearing";
+// fees the bank keeps, nothing is charged yet
+pub const FEE_INCOME: &str = "bank:fee_income";
+
+const CARD_PREFIX: &str = "card:";
+
+pub fn card_account(card_holder: &str) -> String {
+    format!("{}{}", CARD_PREFIX, card_holder)
+}
+
+// the card holder behind a card account, None for the bank's own accounts
+pub fn card_holder_of(account: &str) -> Option<&str> {
+    account.strip_prefix(CARD_PREFIX)
+}
+
+// adds an entry under a new entry id from `ids`, refusing one that doesn't balance;
+// `ledger` only has to hold the entries to be written, nothing is read back from it
+pub fn post(ledger: &mut HashMap<i64, LedgerEntry>, ids: &dyn IdSource, timestamp: i64, trade_id: Option<i64>, memo: &str, postings: Vec<Posting>) -> Result<i64, String> {
+    if postings.len() < 2 {
+        return Err(format!("Ledger entry {} needs at least two postings", memo));
+    }
+    let total: Decimal = postings.iter().map(|p| p.amount).sum();
+    if !total.is_zero() {
+        return Err(format!("Ledger entry {} is off by {}", memo, total));
+    }
+
+    let entry_id = ids.next_id(Sequence::LedgerEntry)?;
+    ledger.insert(entry_id, LedgerEntry {
+        timestamp,
+        trade_id,
+        memo: memo.to_string(),
+        postings,
+    });
+    Ok(entry_id)
+}
+
+pub fn account_balance(ledger: &HashMap<i64, LedgerEntry>, account: &str) -> Decimal {
+    ledger.values()
+        .flat_map(|entry| &entry.postings)
+        .filter(|p| p.account == account)
+        .map(|p| p.amount)
+        .sum()
+}
+
+pub fn account_balances(ledger: &HashMap<i64, LedgerEntry>) -> HashMap<String, Decimal> {
+    let mut balances: HashMap<String, Decimal> = HashMap::new();
+    for posting in ledger.values().flat_map(|entry| &entry.postings) {
+        *balances.entry(posting.account.clone()).or_default() += posting.amount;
+    }
+    balances
+}
+
+// moves `amount` from the bank account `counter` to the card, a negative amount goes the other way
+#[allow(clippy::too_many_arguments)]
+pub fn post_card(
+    ledger: &mut HashMap<i64, LedgerEntry>,
+    ids: &dyn IdSource,
+    data: &mut CardInfo,
+    counter: &str,
+    amount: Decimal,
+    timestamp: i64,
+    trade_id: Option<i64>,
+    memo: &str,
+) -> Result<i64, String> {
+    let postings = vec![
+        Posting::new(&card_account(&data.card_holder), amount),
+        Posting::new(counter, -amount),
+    ];
+    let entry_id = post(ledger, ids, timestamp, trade_id, memo, postings)?;
+    // the stored balance is the running balance of the card's account, reconciliation checks it against the postings
+    data.balance += amount;
+    Ok(entry_id)
+}
+
+// gives cards saved before the ledger existed an opening entry from the treasury, run on startup;
+// `balances` come from the stored ledger and the new entries go to `ledger`
+pub fn open_balances(card_map: &HashMap<u64, CardInfo>, balances: &HashMap<String, Decimal>, ledger: &mut HashMap<i64, LedgerEntry>, ids: &dyn IdSource, now: i64) -> Result<usize, String> {
+    let mut opened = 0;
+    for data in card_map.values() {
+        let account = card_account(&data.card_holder);
+        if balances.contains_key(&account) || data.balance.is_zero() {
+            continue;
+        }
+        let postings = vec![Posting::new(&account, data.balance), Posting::new(TREASURY, -data.balance)];
+        post(ledger, ids, now, None, "opening balance", postings)?;
+        opened += 1;
+    }
+    Ok(opened)
+}
+
+// (card holder, stored balance, ledger balance) for every card the two disagree on
+pub fn check_balances(card_map: &HashMap<u64, CardInfo>, ledger: &HashMap<i64, LedgerEntry>) -> Vec<(String, Decimal, Decimal)> {
+    let balances = account_balances(ledger);
+    card_map.values()
+        .filter_map(|data| {
+            let derived = balances.get(&card_account(&data.card_holder)).copied().unwrap_or_default();
+            (derived != data.balance).then(|| (data.card_holder.clone(), data.balance, derived))
+        })
+        .collect()
+}
@@ -1,4 +1,9 @@
-use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}, fs, io, hash::{DefaultHasher, Hash, Hasher}};
+mod history;
+mod ids;
+mod ledger;
+mod reconcile;
+
+use std::{collections::{HashMap, HashSet}, env, sync::{Arc, RwLock}, time::{SystemTime, UNIX_EPOCH}, fs, io::{self, Write}, path::Path, hash::{DefaultHasher, Hash, Hasher}};
 use base64::Engine;
 use base64::engine::general_purpose;
 use chrono::{Datelike, Local, TimeZone};
@@ -9,18 +14,207 @@ use rust_decimal::{Decimal, prelude::Zero};
 use rust_decimal::prelude::FromPrimitive;
 use sha2::Sha256;
 use serde::{Serialize, de::DeserializeOwned};
-use structure::{CardInfo, DiscordTrade, TradeHistory, TransactionType};
+use structure::{CardInfo, DiscordTrade, LedgerEntry, Posting, TradeHistory, TradeIndex, TransactionType, Transfer};
+
+pub use history::{check_history_query, trade_page};
+pub use ids::{IdFormat, IdSource, Sequence};
+pub use ledger::{account_balance, account_balances, card_account, card_holder_of, check_balances, open_balances, post, post_card, FEE_INCOME, STOCK_CLEARING, TREASURY};
+pub use reconcile::reconcile;
 
 type HmacSha256 = Hmac<Sha256>;
 
-pub fn generate_token(secret: &str, card_number: &str, good_thru: &str, verify_number: &str) -> String {
+pub type SharedKeys = Arc<RwLock<KeyRing>>;
+
+// tokens issued before key ids existed are treated as signed by this key
+pub const LEGACY_KEY_ID: &str = "default";
+const LEGACY_SECRET: &str = "connection_key";
+const RETIRED_KEYS_PATH: &str = "retired_keys.json";
+
+pub fn generate_token(key_id: &str, secret: &str, card_number: &str, good_thru: &str, verify_number: &str) -> String {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
     let payload = format!("{}|{}|{}|{}", card_number, good_thru, verify_number, now);
     let payload_encoded = general_purpose::STANDARD.encode(&payload);
     let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
     mac.update(payload.as_bytes());
     let signature = hex::encode(mac.finalize().into_bytes());
-    format!("{}.{}", payload_encoded, signature)
+    format!("{}.{}.{}", key_id, payload_encoded, signature)
+}
+
+pub fn token_key_id(token: &str) -> &str {
+    match token.split('.').collect::<Vec<_>>().as_slice() {
+        [key_id, _, _] => key_id,
+        _ => LEGACY_KEY_ID,
+    }
+}
+
+pub fn mask_token(token: &str) -> String {
+    let chars: Vec<char> = token.chars().collect();
+    if chars.len() <= 12 {
+        return "*".repeat(chars.len());
+    }
+    let head: String = chars[..6].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", head, tail)
+}
+
+// what a token is presented for, a refresh accepts older tokens than a request does
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TokenUse {
+    // any account-scoped request, up to TOKEN_MAX_AGE
+    Access,
+    // /refresh_token, up to twice TOKEN_MAX_AGE; after that the card has to /connect again
+    Refresh,
+}
+
+// checks the signature, that the token was issued for this card and that it is young enough for `usage`
+pub fn verify_token(keys: &KeyRing, token: &str, card: &CardInfo, usage: TokenUse) -> Result<(), String> {
+    let (key_id, payload_encoded, signature) = match token.split('.').collect::<Vec<_>>().as_slice() {
+        [key_id, payload, signature] => (*key_id, *payload, *signature),
+        [payload, signature] => (LEGACY_KEY_ID, *payload, *signature),
+        _ => return Err(String::from("Malformed token")),
+    };
+
+    let secret = keys.secret(key_id)
+        .ok_or_else(|| String::from("Token was signed by a retired key, please reconnect"))?;
+
+    let payload = general_purpose::STANDARD.decode(payload_encoded).ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| String::from("Malformed token"))?;
+    let signature = hex::decode(signature).map_err(|_| String::from("Malformed token"))?;
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| String::from("Invalid token signature"))?;
+
+    let [card_number, good_thru, verify_number, issued_at] = payload.split('|').collect::<Vec<_>>()[..] else {
+        return Err(String::from("Malformed token"));
+    };
+
+    if card_number != card.card_number || good_thru != card.good_thru || verify_number != card.verify_number {
+        return Err(String::from("Token does not belong to this card"));
+    }
+
+    let issued_at: u64 = issued_at.parse().map_err(|_| String::from("Malformed token"))?;
+    let age = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().saturating_sub(issued_at);
+    match usage {
+        TokenUse::Access if age > keys.max_age => Err(String::from("Token expired, please refresh")),
+        TokenUse::Refresh if age > keys.max_age.saturating_mul(2) => Err(String::from("Token is too old to refresh, please reconnect")),
+        _ => Ok(()),
+    }
+}
+
+pub struct KeyRing {
+    keys: HashMap<String, String>,
+    active: String,
+    retired: HashSet<String>,
+    max_age: u64,
+    // where retire() keeps the retired key ids
+    retired_path: String,
+}
+
+impl KeyRing {
+    // CONNECTION_KEYS=id1=secret1,id2=secret2 and CONNECTION_KEY_ID picks the signing key (default: last listed)
+    pub fn from_env() -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        let mut last = None;
+        match env::var("CONNECTION_KEYS") {
+            Ok(list) => {
+                for pair in list.split(',').filter(|p| !p.trim().is_empty()) {
+                    let Some((id, secret)) = pair.split_once('=') else {
+                        return Err(format!("Invalid connection key entry：{}", pair));
+                    };
+                    let id = id.trim();
+                    if id.is_empty() || id.contains('.') || secret.is_empty() {
+                        return Err(format!("Invalid connection key entry：{}", pair));
+                    }
+                    keys.insert(id.to_string(), secret.to_string());
+                    last = Some(id.to_string());
+                }
+            }
+            Err(_) => {
+                keys.insert(LEGACY_KEY_ID.to_string(), LEGACY_SECRET.to_string());
+                last = Some(LEGACY_KEY_ID.to_string());
+            }
+        }
+
+        let active = match env::var("CONNECTION_KEY_ID") {
+            Ok(id) => id,
+            Err(_) => last.ok_or_else(|| String::from("No connection key configured"))?,
+        };
+
+        let retired: HashSet<String> = if Path::new(RETIRED_KEYS_PATH).exists() {
+            get_map(RETIRED_KEYS_PATH)?
+        } else {
+            HashSet::new()
+        };
+
+        // TOKEN_MAX_AGE in seconds, 30 days by default
+        let max_age = match env::var("TOKEN_MAX_AGE") {
+            Ok(age) => age.parse::<u64>().map_err(|e| format!("Invalid TOKEN_MAX_AGE {} ：{}", age, e))?,
+            Err(_) => 30 * 86400,
+        };
+
+        Self::new(keys, active, retired, max_age)
+    }
+
+    pub fn new(keys: HashMap<String, String>, active: String, retired: HashSet<String>, max_age: u64) -> Result<Self, String> {
+        if !keys.contains_key(&active) {
+            return Err(format!("Active connection key {} is not configured", active));
+        }
+        if retired.contains(&active) {
+            return Err(format!("Active connection key {} has been retired", active));
+        }
+        Ok(KeyRing { keys, active, retired, max_age, retired_path: RETIRED_KEYS_PATH.to_string() })
+    }
+
+    pub fn with_retired_path(mut self, path: &str) -> Self {
+        self.retired_path = path.to_string();
+        self
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.active
+    }
+
+    pub fn sign(&self, card_number: &str, good_thru: &str, verify_number: &str) -> String {
+        generate_token(&self.active, &self.keys[&self.active], card_number, good_thru, verify_number)
+    }
+
+    // secret for a key id that is configured and not retired
+    pub fn secret(&self, key_id: &str) -> Option<&str> {
+        if self.retired.contains(key_id) {
+            return None;
+        }
+        self.keys.get(key_id).map(String::as_str)
+    }
+
+    pub fn key_ids(&self) -> (Vec<String>, Vec<String>) {
+        let mut usable: Vec<String> = self.keys.keys().filter(|id| !self.retired.contains(*id)).cloned().collect();
+        let mut retired: Vec<String> = self.retired.iter().cloned().collect();
+        usable.sort();
+        retired.sort();
+        (usable, retired)
+    }
+
+    pub fn retire(&mut self, key_id: &str) -> Result<(), String> {
+        if key_id == self.active {
+            return Err(String::from("Can't retire the key currently used for signing"));
+        }
+        if !self.keys.contains_key(key_id) && key_id != LEGACY_KEY_ID {
+            return Err(format!("Unknown key id：{}", key_id));
+        }
+        self.retired.insert(key_id.to_string());
+        write_json_to_file(&self.retired_path, &self.retired)
+            .map_err(|e| format!("Failed to write {} ：{}", self.retired_path, e))
+    }
+}
+
+// ADMIN_TOKEN unset or empty disables the admin endpoints
+pub fn is_admin(token: &str) -> bool {
+    match env::var("ADMIN_TOKEN") {
+        Ok(admin) => !admin.is_empty() && admin == token,
+        Err(_) => false,
+    }
 }
 
 pub fn generate_n_digit(seed: u64, digits: u32) -> u64 {
@@ -52,7 +246,26 @@ pub fn get_map<T: Serialize + DeserializeOwned>(path: &str) -> Result<T, String>
 
 pub fn write_json_to_file<T: Serialize>(path: &str, input: &T) -> Result<(), io::Error> {
     let json_str = serde_json::to_string_pretty(input)?;
-    fs::write(path, &json_str)?;
+    write_atomic(path, json_str.as_bytes())
+}
+
+pub fn write_atomic(path: &str, content: &[u8]) -> Result<(), io::Error> {
+    // write a sibling temp file, fsync it, then rename over the target so readers never see half a file
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+
+    let dir = match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    // persisting the rename needs the directory entry flushed too; not supported on every platform
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
     Ok(())
 }
 
@@ -87,10 +300,7 @@ pub fn gen_card_num(scheme: &str, mixture: u64) -> Result<String, String> {
 }
 
 pub fn gen_card(scheme: String, card_type: String, mixture: u64, holder: &str) -> Result<CardInfo, String> {
-    let card_number = match gen_card_num(&scheme, mixture) {
-        Ok(n) => n,
-        Err(e) => return Err(e),
-    };
+    let card_number = gen_card_num(&scheme, mixture)?;
 
     let verify_number = generate_n_digit(mixture, 3);
     let good_thru = generate_yymm();
@@ -104,6 +314,7 @@ pub fn gen_card(scheme: String, card_type: String, mixture: u64, holder: &str) -
         balance: Decimal::zero(),
         connection: None,
         transaction: None,
+        leaderboard_opt_out: false,
     };
 
     Ok(card_info)
@@ -119,72 +330,65 @@ pub fn get_day_end(unix_time: i64) -> i64 {
     end_of_day.timestamp()
 }
 
-pub fn handler_transaction(id: DiscordTrade, card_map: &mut HashMap<u64, CardInfo>) -> Result<String, String> {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    let last_trade: i64;
+// the amount of a /dc_trade, None unless it is a positive number
+pub fn trade_amount(transaction_type: &TransactionType) -> Option<Decimal> {
+    let (TransactionType::Credit { amount } | TransactionType::Debit { amount }) = transaction_type;
+    Decimal::from_f64(*amount).filter(|amount| *amount > Decimal::zero())
+}
 
-    let mut trade_map: HashMap<i64, TradeHistory> = match get_map("trade.json") {
-        Ok(map) => map,
-        Err(e) => {
-            eprintln!("Error： {}", e);
-            return Err(String::from("Server error, please call admin fixing!"));
-        }
-    };
+pub fn handler_transaction(
+    id: DiscordTrade,
+    card_map: &mut HashMap<u64, CardInfo>,
+    trade_map: &mut HashMap<i64, TradeHistory>,
+    ledger: &mut HashMap<i64, LedgerEntry>,
+    ids: &dyn IdSource,
+) -> Result<String, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
 
     let data = match card_map.values_mut().find(|data| data.card_holder == id.card_holder) {
         Some(card) => card,
         None => return Err(String::from("No card found!")),
     };
 
-    if trade_map.is_empty() {
-        last_trade = 0;
-    } else {
-        let Some(last) = trade_map.keys().max() else { todo!() };
-        last_trade = *last;
-    };
-
+    // a negative deposit or withdrawal would move money the other way
     let new_balance = match id.transaction_type {
         TransactionType::Credit { amount } => {
-            match Decimal::from_f64(amount) {
+            match trade_amount(&id.transaction_type) {
                 Some(price) => {
-                    data.balance += price;
-                    let transaction_map = data.transaction.get_or_insert_with(HashMap::new);
-                    transaction_map.insert(now, last_trade + 1);
+                    let trade_id = ids.next_id(Sequence::Trade)?;
+                    data.transaction.get_or_insert_with(TradeIndex::default).push(now, trade_id);
 
                     let trade_info = TradeHistory {
                         timestamp: now,
                         transaction_type: TransactionType::Credit { amount },
                         target_user: id.target_user,
+                        linked_trade: None,
+                        reason: None,
                     };
 
-                    trade_map.insert(last_trade + 1, trade_info);
-                    if let Err(e) = write_json_to_file("trade.json", &trade_map) {
-                        println!("Error in writing trade json: {}", e);
-                        return Err(String::from("Server error, please call admin fixing!"));
-                    }
+                    trade_map.insert(trade_id, trade_info);
+                    post_card(ledger, ids, data, TREASURY, price, now, Some(trade_id), "deposit")?;
                     Some(data.balance)
                 }
                 _ => None,
             }
         }
         TransactionType::Debit { amount } => {
-            match Decimal::from_f64(amount) {
+            match trade_amount(&id.transaction_type) {
                 Some(price) if check_balance(&data.balance, price) => {
-                    data.balance -= price;
-                    let transaction_map = data.transaction.get_or_insert_with(HashMap::new);
-                    transaction_map.insert(now, last_trade + 1);
+                    let trade_id = ids.next_id(Sequence::Trade)?;
+                    data.transaction.get_or_insert_with(TradeIndex::default).push(now, trade_id);
 
                     let trade_info = TradeHistory {
                         timestamp: now,
                         transaction_type: TransactionType::Debit { amount },
                         target_user: id.target_user,
+                        linked_trade: None,
+                        reason: None,
                     };
 
-                    trade_map.insert(last_trade + 1, trade_info);
-                    if let Err(e) = write_json_to_file("trade.json", &trade_map) {
-                        println!("Error in writing trade json: {}", e);
-                        return Err(String::from("Server error, please call admin fixing!"));
-                    }
+                    trade_map.insert(trade_id, trade_info);
+                    post_card(ledger, ids, data, TREASURY, -price, now, Some(trade_id), "withdrawal")?;
                     Some(data.balance)
                 }
                 _ => None,
@@ -197,11 +401,77 @@ pub fn handler_transaction(id: DiscordTrade, card_map: &mut HashMap<u64, CardInf
         None => return Err(String::from("Transaction failed, please check the amount format")),
     };
 
-    if let Err(e) = write_json_to_file("account.json", &card_map) {
-        println!("Error in writing card json: {}", e);
-        return Err(String::from("Server error, please call admin fixing!"));
-    }
-
     let message = format!("Transaction successful! Balance : {} USD", balance);
     Ok(message)
 }
+
+pub fn handler_transfer(
+    transfer: Transfer,
+    card_map: &mut HashMap<u64, CardInfo>,
+    trade_map: &mut HashMap<i64, TradeHistory>,
+    ledger: &mut HashMap<i64, LedgerEntry>,
+    ids: &dyn IdSource,
+) -> Result<(Decimal, String), String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+    let amount = match Decimal::from_f64(transfer.amount) {
+        Some(a) if a > Decimal::zero() => a,
+        _ => return Err(String::from("Transfer failed, please check the amount format")),
+    };
+
+    let Some(sender_id) = card_map.iter()
+        .find(|(_, data)| data.card_holder == transfer.card_holder)
+        .map(|(id, _)| *id) else {
+        return Err(String::from("No card found!"));
+    };
+
+    let Some(recipient_id) = card_map.iter()
+        .find(|(_, data)| data.card_holder == transfer.recipient || data.card_number == transfer.recipient)
+        .map(|(id, _)| *id) else {
+        return Err(String::from("Recipient not found!"));
+    };
+
+    if sender_id == recipient_id {
+        return Err(String::from("You can't transfer to yourself!"));
+    }
+
+    if !check_balance(&card_map[&sender_id].balance, amount) {
+        return Err(String::from("Insufficient balance"));
+    }
+
+    let debit_id = ids.next_id(Sequence::Trade)?;
+    let credit_id = ids.next_id(Sequence::Trade)?;
+    let sender_holder = card_map[&sender_id].card_holder.clone();
+    let recipient_holder = card_map[&recipient_id].card_holder.clone();
+
+    trade_map.insert(debit_id, TradeHistory {
+        timestamp: now,
+        transaction_type: TransactionType::Debit { amount: transfer.amount },
+        target_user: recipient_holder.clone(),
+        linked_trade: Some(credit_id),
+        reason: None,
+    });
+    trade_map.insert(credit_id, TradeHistory {
+        timestamp: now,
+        transaction_type: TransactionType::Credit { amount: transfer.amount },
+        target_user: sender_holder.clone(),
+        linked_trade: Some(debit_id),
+        reason: None,
+    });
+
+    let postings = vec![
+        Posting::new(&card_account(&sender_holder), -amount),
+        Posting::new(&card_account(&recipient_holder), amount),
+    ];
+    post(ledger, ids, now, Some(debit_id), "transfer", postings)?;
+
+    let recipient = card_map.get_mut(&recipient_id).unwrap();
+    recipient.balance += amount;
+    recipient.transaction.get_or_insert_with(TradeIndex::default).push(now, credit_id);
+
+    let sender = card_map.get_mut(&sender_id).unwrap();
+    sender.balance -= amount;
+    sender.transaction.get_or_insert_with(TradeIndex::default).push(now, debit_id);
+
+    Ok((sender.balance, recipient_holder))
+}
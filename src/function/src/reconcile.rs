@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::FromPrimitive;
+use structure::{CardInfo, Discrepancy, LedgerEntry, Leverage, ReconciliationReport, StockHold, TradeHistory, TransactionType};
+use crate::ledger::{card_holder_of, check_balances};
+
+// trade amounts are kept as f64, so a replayed balance within a cent of the stored one agrees
+fn tolerance() -> Decimal {
+    Decimal::new(1, 2)
+}
+
+fn signed_amount(trade: &TradeHistory) -> Decimal {
+    match trade.transaction_type {
+        TransactionType::Credit { amount } => Decimal::from_f64(amount).unwrap_or_default(),
+        TransactionType::Debit { amount } => -Decimal::from_f64(amount).unwrap_or_default(),
+    }
+}
+
+// the card whose posting, in the ledger entry of this trade or of its linked trade, moved money the same way
+fn ledger_owner(trade_id: i64, trade: &TradeHistory, ledger: &HashMap<i64, LedgerEntry>) -> Option<String> {
+    let amount = signed_amount(trade);
+    let ids = [Some(trade_id), trade.linked_trade];
+    ledger.values()
+        .filter(|entry| entry.trade_id.is_some() && ids.contains(&entry.trade_id))
+        .flat_map(|entry| &entry.postings)
+        .find(|p| (p.amount - amount).abs() <= tolerance() && card_holder_of(&p.account).is_some())
+        .and_then(|p| card_holder_of(&p.account).map(String::from))
+}
+
+// compares the stored balances, trade indexes, trades, holdings and ledger with each other;
+// nothing is changed, every problem found ends up in the report
+pub fn reconcile(
+    card_map: &HashMap<u64, CardInfo>,
+    trade_map: &HashMap<i64, TradeHistory>,
+    holdings: &HashMap<String, Vec<StockHold>>,
+    ledger: &HashMap<i64, LedgerEntry>,
+    now: i64,
+) -> ReconciliationReport {
+    let mut discrepancies = Vec::new();
+    let mut cards: Vec<&CardInfo> = card_map.values().collect();
+    cards.sort_by(|a, b| a.card_holder.cmp(&b.card_holder));
+
+    let mut indexed: HashSet<i64> = HashSet::new();
+    for data in &cards {
+        let mut replayed = Decimal::ZERO;
+        for trade_ref in data.transaction.iter().flat_map(|index| index.iter()) {
+            indexed.insert(trade_ref.trade_id);
+            match trade_map.get(&trade_ref.trade_id) {
+                Some(trade) => replayed += signed_amount(trade),
+                None => discrepancies.push(Discrepancy::MissingTrade {
+                    card_holder: data.card_holder.clone(),
+                    timestamp: trade_ref.timestamp,
+                    trade_id: trade_ref.trade_id,
+                }),
+            }
+        }
+        if (replayed - data.balance).abs() > tolerance() {
+            discrepancies.push(Discrepancy::BalanceMismatch {
+                card_holder: data.card_holder.clone(),
+                stored: data.balance,
+                replayed,
+            });
+        }
+    }
+
+    let mut mismatched = check_balances(card_map, ledger);
+    mismatched.sort_by(|a, b| a.0.cmp(&b.0));
+    for (card_holder, stored, ledger) in mismatched {
+        discrepancies.push(Discrepancy::LedgerMismatch { card_holder, stored, ledger });
+    }
+
+    let mut orphans: Vec<(i64, &TradeHistory)> = trade_map.iter()
+        .filter(|(id, _)| !indexed.contains(id))
+        .map(|(id, trade)| (*id, trade))
+        .collect();
+    orphans.sort_by_key(|(id, _)| *id);
+    for (trade_id, trade) in orphans {
+        // trades from before the ledger have no entry, a card indexing another trade at the same second is the next best guess;
+        // the old per-second index of account.json kept only one of them
+        let owner = ledger_owner(trade_id, trade, ledger).or_else(|| {
+            let mut same_second = cards.iter()
+                .filter(|data| data.transaction.as_ref().is_some_and(|index| index.first_at(trade.timestamp).is_some()));
+            match (same_second.next(), same_second.next()) {
+                (Some(data), None) => Some(data.card_holder.clone()),
+                _ => None,
+            }
+        });
+        let kept = owner.as_ref()
+            .and_then(|owner| cards.iter().find(|data| &data.card_holder == owner))
+            .and_then(|data| data.transaction.as_ref()?.first_at(trade.timestamp));
+
+        discrepancies.push(match (owner, kept) {
+            (Some(card_holder), Some(kept)) => Discrepancy::DuplicateTimestamp {
+                card_holder,
+                timestamp: trade.timestamp,
+                kept,
+                lost: trade_id,
+            },
+            (card_holder, _) => Discrepancy::OrphanedTrade {
+                trade_id,
+                timestamp: trade.timestamp,
+                card_holder,
+            },
+        });
+    }
+
+    let mut holders: Vec<&String> = holdings.keys().collect();
+    holders.sort();
+    for card_holder in holders {
+        let has_card = cards.iter().any(|data| &data.card_holder == card_holder);
+        for hold in &holdings[card_holder] {
+            let reason = if !has_card {
+                Some(String::from("No card for this card holder"))
+            } else if hold.stock.hand <= Decimal::ZERO {
+                Some(format!("Hand {} is not positive", hold.stock.hand))
+            } else {
+                Leverage::try_from(hold.stock.leverage).err()
+            };
+            if let Some(reason) = reason {
+                discrepancies.push(Discrepancy::DanglingHold {
+                    card_holder: card_holder.clone(),
+                    symbol: hold.stock.symbol.clone(),
+                    timestamp: hold.timestamp,
+                    reason,
+                });
+            }
+        }
+    }
+
+    ReconciliationReport {
+        checked_at: now,
+        cards: card_map.len(),
+        trades: trade_map.len(),
+        holds: holdings.values().map(Vec::len).sum(),
+        discrepancies,
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use structure::{TradeAction, TradeHistory, TradeHistoryQuery, TradeIndex, TradePage, TradeSort, TransactionType};
+use function::{check_history_query, trade_page};
+
+fn query() -> TradeHistoryQuery {
+    TradeHistoryQuery {
+        card_holder: String::from("alice"),
+        token: String::new(),
+        target: String::from("discord"),
+        from: None,
+        to: None,
+        action: None,
+        counterparty: None,
+        min_amount: None,
+        max_amount: None,
+        sort: TradeSort::Newest,
+        cursor: None,
+        limit: None,
+    }
+}
+
+// trade ids are 100 + seq, one trade per second starting at 1000
+struct History {
+    index: TradeIndex,
+    trades: HashMap<i64, TradeHistory>,
+}
+
+impl History {
+    fn new() -> Self {
+        History { index: TradeIndex::default(), trades: HashMap::new() }
+    }
+
+    fn push(&mut self, transaction_type: TransactionType, target_user: &str) {
+        let trade_id = 101 + self.trades.len() as i64;
+        let timestamp = 1000 + self.trades.len() as i64;
+        self.index.push(timestamp, trade_id);
+        self.trades.insert(trade_id, TradeHistory {
+            timestamp,
+            transaction_type,
+            target_user: target_user.to_string(),
+            linked_trade: None,
+            reason: None,
+        });
+    }
+
+    fn page(&self, query: &TradeHistoryQuery) -> TradePage {
+        trade_page(Some(&self.index), &self.trades, query)
+    }
+}
+
+fn credits(count: usize) -> History {
+    let mut history = History::new();
+    for amount in 1..=count {
+        history.push(TransactionType::Credit { amount: amount as f64 }, "shop");
+    }
+    history
+}
+
+fn ids(page: &TradePage) -> Vec<i64> {
+    page.trades.iter().map(|entry| entry.id).collect()
+}
+
+#[test]
+fn cursor_pages_through_every_trade_once() {
+    let history = credits(5);
+
+    let mut query = query();
+    query.limit = Some(2);
+    let mut seen = Vec::new();
+    loop {
+        let page = history.page(&query);
+        seen.extend(ids(&page));
+        match page.next_cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(seen, vec![105, 104, 103, 102, 101]);
+
+    let mut query = self::query();
+    query.sort = TradeSort::Oldest;
+    query.limit = Some(3);
+    let first = history.page(&query);
+    assert_eq!(ids(&first), vec![101, 102, 103]);
+    assert_eq!(first.next_cursor, Some(3));
+    query.cursor = first.next_cursor;
+    let last = history.page(&query);
+    assert_eq!(ids(&last), vec![104, 105]);
+    assert_eq!(last.next_cursor, None);
+}
+
+#[test]
+fn new_trades_do_not_shift_the_next_page() {
+    let mut history = credits(4);
+
+    let mut query = query();
+    query.limit = Some(2);
+    let first = history.page(&query);
+    assert_eq!(ids(&first), vec![104, 103]);
+
+    history.push(TransactionType::Debit { amount: 1.0 }, "shop");
+    history.push(TransactionType::Debit { amount: 2.0 }, "shop");
+
+    query.cursor = first.next_cursor;
+    let second = history.page(&query);
+    assert_eq!(ids(&second), vec![102, 101]);
+    assert_eq!(second.next_cursor, None);
+}
+
+#[test]
+fn action_counterparty_and_amount_filters() {
+    let mut history = History::new();
+    history.push(TransactionType::Credit { amount: 10.0 }, "shop");
+    history.push(TransactionType::Debit { amount: 20.0 }, "shop");
+    history.push(TransactionType::Debit { amount: 30.0 }, "bob");
+    history.push(TransactionType::Credit { amount: 40.0 }, "bob");
+
+    let mut debits = query();
+    debits.action = Some(TradeAction::Debit);
+    assert_eq!(ids(&history.page(&debits)), vec![103, 102]);
+
+    let mut bob = query();
+    bob.counterparty = Some(String::from("bob"));
+    assert_eq!(ids(&history.page(&bob)), vec![104, 103]);
+
+    let mut between = query();
+    between.min_amount = Some(Decimal::from(20));
+    between.max_amount = Some(Decimal::from(30));
+    assert_eq!(ids(&history.page(&between)), vec![103, 102]);
+
+    let mut all = query();
+    all.action = Some(TradeAction::Credit);
+    all.counterparty = Some(String::from("bob"));
+    all.min_amount = Some(Decimal::from(40));
+    assert_eq!(ids(&history.page(&all)), vec![104]);
+}
+
+#[test]
+fn limit_is_clamped_between_one_and_a_hundred() {
+    let history = credits(120);
+
+    let mut query = query();
+    query.limit = Some(0);
+    let page = history.page(&query);
+    assert_eq!(ids(&page), vec![220]);
+    assert_eq!(page.next_cursor, Some(120));
+
+    query.limit = Some(1000);
+    let page = history.page(&query);
+    assert_eq!(page.trades.len(), 100);
+    assert_eq!(page.next_cursor, Some(21));
+
+    query.limit = None;
+    assert_eq!(history.page(&query).trades.len(), 20);
+}
+
+#[test]
+fn from_and_to_select_a_half_open_range() {
+    let history = credits(5);
+
+    let mut query = query();
+    query.from = Some(1001);
+    query.to = Some(1003);
+    assert!(check_history_query(&query).is_ok());
+    assert_eq!(ids(&history.page(&query)), vec![103, 102]);
+
+    query.to = Some(1001);
+    let e = check_history_query(&query).unwrap_err();
+    assert_eq!(e.field, "to");
+
+    let mut amounts = self::query();
+    amounts.min_amount = Some(Decimal::from(5));
+    amounts.max_amount = Some(Decimal::from(4));
+    assert_eq!(check_history_query(&amounts).unwrap_err().field, "max_amount");
+}
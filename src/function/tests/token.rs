@@ -0,0 +1,43 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use function::{verify_token, TokenUse};
+use storage::test_util::{card, key_ring};
+
+const MAX_AGE: u64 = 3600;
+
+// the same format as `generate_token`, issued `age` seconds ago
+fn token_of_age(card_number: &str, good_thru: &str, verify_number: &str, age: u64) -> String {
+    let issued_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - age;
+    let payload = format!("{}|{}|{}|{}", card_number, good_thru, verify_number, issued_at);
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+    mac.update(payload.as_bytes());
+    format!("k1.{}.{}", general_purpose::STANDARD.encode(&payload), hex::encode(mac.finalize().into_bytes()))
+}
+
+#[test]
+fn expired_tokens_can_only_be_refreshed_within_the_window() {
+    let keys = key_ring(MAX_AGE);
+    let card = card("alice", 1, 0);
+    let token = |age| token_of_age(&card.card_number, &card.good_thru, &card.verify_number, age);
+
+    assert!(verify_token(&keys, &token(10), &card, TokenUse::Access).is_ok());
+
+    let expired = token(MAX_AGE + 10);
+    assert_eq!(verify_token(&keys, &expired, &card, TokenUse::Access), Err(String::from("Token expired, please refresh")));
+    assert!(verify_token(&keys, &expired, &card, TokenUse::Refresh).is_ok());
+
+    let stale = token(2 * MAX_AGE + 10);
+    assert_eq!(verify_token(&keys, &stale, &card, TokenUse::Refresh), Err(String::from("Token is too old to refresh, please reconnect")));
+}
+
+#[test]
+fn tokens_of_another_card_are_refused() {
+    let keys = key_ring(MAX_AGE);
+    let card = card("alice", 1, 0);
+    let token = token_of_age(&card.card_number, &card.good_thru, "999", 0);
+
+    assert_eq!(verify_token(&keys, &token, &card, TokenUse::Access), Err(String::from("Token does not belong to this card")));
+}
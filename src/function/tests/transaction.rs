@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use structure::{CardInfo, DiscordTrade, TransactionType};
+use function::{account_balances, card_account, handler_transaction, TREASURY};
+use storage::{Changes, MemoryStorage, Storage};
+use storage::test_util::storage_with;
+
+fn trade(card_holder: &str, transaction_type: TransactionType) -> DiscordTrade {
+    DiscordTrade {
+        card_holder: card_holder.to_string(),
+        target_user: String::from("shop"),
+        transaction_type,
+        token: String::new(),
+        target: String::from("discord"),
+        idempotency_key: None,
+    }
+}
+
+// runs one /dc_trade the way the handler does: load the card, apply, save what changed
+fn run(storage: &MemoryStorage, id: DiscordTrade) -> Result<String, String> {
+    let mut card_map = storage.load_accounts_of(&[&id.card_holder])?;
+    let mut trade_map = HashMap::new();
+    let mut ledger = HashMap::new();
+    let message = handler_transaction(id, &mut card_map, &mut trade_map, &mut ledger, storage)?;
+    storage.save_changes(Changes { accounts: Some(&card_map), trades: Some(&trade_map), ledger: Some(&ledger), ..Changes::default() })?;
+    Ok(message)
+}
+
+fn card(storage: &MemoryStorage, card_holder: &str) -> CardInfo {
+    storage.load_accounts_of(&[card_holder]).unwrap().into_values().next().unwrap()
+}
+
+#[test]
+fn deposit_and_withdrawal_move_balance_trades_and_ledger() {
+    let storage = storage_with(&[("alice", 0)]);
+
+    run(&storage, trade("alice", TransactionType::Credit { amount: 100.0 })).unwrap();
+    let message = run(&storage, trade("alice", TransactionType::Debit { amount: 30.0 })).unwrap();
+    assert_eq!(message, "Transaction successful! Balance : 70 USD");
+
+    let data = card(&storage, "alice");
+    assert_eq!(data.balance, Decimal::from(70));
+    assert_eq!(data.transaction.as_ref().map(|index| index.iter().count()), Some(2));
+    assert_eq!(storage.load_trades().unwrap().len(), 2);
+
+    let balances = account_balances(&storage.load_ledger().unwrap());
+    assert_eq!(balances[&card_account("alice")], Decimal::from(70));
+    assert_eq!(balances[TREASURY], Decimal::from(-70));
+}
+
+#[test]
+fn withdrawal_beyond_balance_is_refused() {
+    let storage = storage_with(&[("alice", 20)]);
+
+    let result = run(&storage, trade("alice", TransactionType::Debit { amount: 20.5 }));
+    assert_eq!(result, Err(String::from("Transaction failed, please check the amount format")));
+
+    let data = card(&storage, "alice");
+    assert_eq!(data.balance, Decimal::from(20));
+    assert!(data.transaction.is_none());
+    assert!(storage.load_trades().unwrap().is_empty());
+    assert!(storage.load_ledger().unwrap().is_empty());
+}
+
+#[test]
+fn unknown_card_is_refused() {
+    let storage = storage_with(&[("alice", 20)]);
+
+    let result = run(&storage, trade("bob", TransactionType::Credit { amount: 5.0 }));
+    assert_eq!(result, Err(String::from("No card found!")));
+    assert!(storage.load_trades().unwrap().is_empty());
+}
+
+#[test]
+fn amounts_that_are_not_positive_are_refused() {
+    let storage = storage_with(&[("alice", 20)]);
+
+    for transaction_type in [
+        TransactionType::Credit { amount: -5.0 },
+        TransactionType::Credit { amount: 0.0 },
+        TransactionType::Debit { amount: -5.0 },
+        TransactionType::Debit { amount: f64::NAN },
+    ] {
+        let result = run(&storage, trade("alice", transaction_type));
+        assert_eq!(result, Err(String::from("Transaction failed, please check the amount format")));
+    }
+
+    assert_eq!(card(&storage, "alice").balance, Decimal::from(20));
+    assert!(storage.load_trades().unwrap().is_empty());
+    assert!(storage.load_ledger().unwrap().is_empty());
+}
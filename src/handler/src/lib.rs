@@ -1,12 +1,13 @@
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::time::{SystemTime, UNIX_EPOCH};
-use axum::{extract::Json, response::IntoResponse, http::StatusCode};
+use axum::{extract::{Json, State}, response::IntoResponse, http::StatusCode};
 use serde_json::json;
-use structure::{Identification, CardInfo, TargetVerify, TargetInfo, DiscordTrade, TradeHistory, RegisterInfo};
-use function::{generate_token, gen_card, hash_str_to_u64, handler_transaction, get_day_end, write_json_to_file, get_map, get_card_name};
+use structure::{AccountAuth, AdminRequest, Identification, CardInfo, TargetVerify, TargetInfo, DiscordTrade, TradeHistory, TradeHistoryQuery, LedgerEntry, RegisterInfo, RetireKey, RevokeConnection, Transfer};
+use function::{check_history_query, gen_card, hash_str_to_u64, handler_transaction, handler_transfer, get_card_name, is_admin, mask_token, trade_amount, trade_page, verify_token, SharedKeys, TokenUse};
+use storage::{reconcile_storage, Changes, IdempotencyStore, SharedIdempotency, SharedLocks, SharedStorage};
+use auth::{get_connected_card, Verified};
 
-pub async fn sign_up_discord(Json(info): Json<RegisterInfo>) -> impl IntoResponse {
+pub async fn sign_up_discord(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Json(info): Json<RegisterInfo>) -> impl IntoResponse {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
     let hash_id = hash_str_to_u64(&info.discord_id);
     let mixture = now + hash_id;
@@ -22,7 +23,8 @@ pub async fn sign_up_discord(Json(info): Json<RegisterInfo>) -> impl IntoRespons
     let good_thru = &card_account.good_thru.clone();
     let verify_number = &card_account.verify_number.clone();
 
-    let mut all_data: HashMap<u64, CardInfo> = match get_map("account.json") {
+    let _store = locks.store().await;
+    let existing: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&info.discord_id]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -30,13 +32,13 @@ pub async fn sign_up_discord(Json(info): Json<RegisterInfo>) -> impl IntoRespons
         }
     };
 
-    if all_data.contains_key(&hash_id) {
+    if !existing.is_empty() {
         return (StatusCode::INTERNAL_SERVER_ERROR, "You have already signed up!").into_response();
     }
 
-    all_data.insert(hash_id, card_account);
+    let new_card = HashMap::from([(hash_id, card_account)]);
 
-    if let Err(e) = write_json_to_file("account.json", &all_data) {
+    if let Err(e) = storage.save_accounts(&new_card) {
         eprintln!("Error in write account： {}", e);
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     }
@@ -48,8 +50,18 @@ pub async fn sign_up_discord(Json(info): Json<RegisterInfo>) -> impl IntoRespons
     }))).into_response()
 }
 
-pub async fn discord_transaction(Json(id): Json<DiscordTrade>) -> impl IntoResponse {
-    let mut card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
+pub async fn discord_transaction(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(idempotency): State<SharedIdempotency>, Verified(id): Verified<DiscordTrade>) -> impl IntoResponse {
+    if trade_amount(&id.transaction_type).is_none() {
+        return (StatusCode::BAD_REQUEST, "Transaction failed, please check the amount format").into_response();
+    }
+    let _account = locks.account(&id.card_holder).await;
+    let replay_key = IdempotencyStore::key("dc_trade", &id.card_holder, id.idempotency_key.as_deref());
+    if let Some(response) = idempotency.replay(storage.as_ref(), replay_key.as_deref()) {
+        return response;
+    }
+
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -57,18 +69,65 @@ pub async fn discord_transaction(Json(id): Json<DiscordTrade>) -> impl IntoRespo
         }
     };
 
-    let result = match handler_transaction(id, &mut card_map) {
+    let mut trade_map: HashMap<i64, TradeHistory> = HashMap::new();
+    let mut ledger: HashMap<i64, LedgerEntry> = HashMap::new();
+
+    let result = match handler_transaction(id, &mut card_map, &mut trade_map, &mut ledger, storage.as_ref()) {
         Ok(message) => message,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
     };
+    let (responses, response) = match idempotency.capture(replay_key, (StatusCode::OK, result).into_response()).await {
+        Ok(captured) => captured,
+        Err(response) => return response,
+    };
+
+    let changes = Changes { accounts: Some(&card_map), trades: Some(&trade_map), ledger: Some(&ledger), responses: responses.as_ref(), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing trade: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+
+    response
+}
+
+pub async fn transfer(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Verified(transfer): Verified<Transfer>) -> impl IntoResponse {
+    let _account = locks.account(&transfer.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&transfer.card_holder, &transfer.recipient]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
 
-    (StatusCode::OK, result).into_response()
+    let mut trade_map: HashMap<i64, TradeHistory> = HashMap::new();
+    let mut ledger: HashMap<i64, LedgerEntry> = HashMap::new();
+
+    let amount = transfer.amount;
+    let (balance, recipient) = match handler_transfer(transfer, &mut card_map, &mut trade_map, &mut ledger, storage.as_ref()) {
+        Ok(result) => result,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let changes = Changes { accounts: Some(&card_map), trades: Some(&trade_map), ledger: Some(&ledger), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing transfer: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+
+    (StatusCode::OK, Json(json!({
+        "status": "ok",
+        "recipient": recipient,
+        "amount": amount,
+        "balance": balance,
+    }))).into_response()
 }
 
-pub async fn connect_verify(Json(target): Json<TargetVerify>) -> impl IntoResponse {
-    //I will make a key system later?
-    let connect_key: String = String::from("connection_key");
-    let mut card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
+pub async fn connect_verify(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(keys): State<SharedKeys>, Json(target): Json<TargetVerify>) -> impl IntoResponse {
+    let _account = locks.account(&target.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&target.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -80,26 +139,74 @@ pub async fn connect_verify(Json(target): Json<TargetVerify>) -> impl IntoRespon
         return (StatusCode::BAD_REQUEST, "No card found!").into_response();
     };
 
-    let token = generate_token(
-        &connect_key,
-        &card.card_number.to_string(),
-        &card.good_thru.to_string(),
-        &card.verify_number.to_string(),
-    );
+    if card.card_number != target.card_number || card.good_thru != target.good_thru || card.verify_number != target.verify_number {
+        return (StatusCode::UNAUTHORIZED, "Card details do not match").into_response();
+    }
+
+    let keys = keys.read().unwrap_or_else(|e| e.into_inner());
+    let token = keys.sign(&card.card_number, &card.good_thru, &card.verify_number);
+
+    // a token is only ever handed out once, a lost one has to be revoked before connecting again
+    let connected = card.connection.as_ref()
+        .and_then(|map| map.get(&target.target))
+        .is_some_and(|infos| infos.iter().any(|info| {
+            info.target == target.target && verify_token(&keys, &info.token, card, TokenUse::Refresh).is_ok()
+        }));
+    if connected {
+        return Json(json!({ "status": "exists" })).into_response();
+    }
 
+    // a token too old to refresh or signed by a retired key is replaced
     let connection_map = card.connection.get_or_insert_with(HashMap::new);
     let connections = connection_map.entry(target.target.clone()).or_insert_with(Vec::new);
+    connections.retain(|info| info.target != target.target);
+    connections.push(TargetInfo { target: target.target.clone(), token: token.clone()});
 
-    if let Some(existing) = connections.iter().find(|info| info.target == target.target) {
-        return Json(json!({
-        "status": "exists",
-        "token": existing.token
-    })).into_response();
+    if let Err(e) = storage.save_accounts(&card_map) {
+        println!("Error in writing card json: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     }
 
-    connections.push(TargetInfo { target: target.target.clone(), token: token.clone()});
+    Json(json!({
+        "status": "ok",
+        "token": token
+    })).into_response()
+}
+
+pub async fn refresh_token(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(keys): State<SharedKeys>, Json(id): Json<AccountAuth>) -> impl IntoResponse {
+    let _account = locks.account(&id.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
 
-    if let Err(e) = write_json_to_file("account.json", &card_map) {
+    let keys = keys.read().unwrap_or_else(|e| e.into_inner());
+
+    // an expired token may be exchanged within the refresh window as long as it is still the stored one and correctly signed
+    let card = match get_connected_card(&card_map, &id.card_holder, &id.target, &id.token) {
+        Ok(card) => card,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = verify_token(&keys, &id.token, card, TokenUse::Refresh) {
+        return (StatusCode::UNAUTHORIZED, e).into_response();
+    }
+
+    let Some(card) = card_map.values_mut().find(|data| data.card_holder == id.card_holder) else {
+        return (StatusCode::UNAUTHORIZED, "No card holder found").into_response();
+    };
+
+    let token = keys.sign(&card.card_number, &card.good_thru, &card.verify_number);
+    if let Some(connections) = card.connection.as_mut().and_then(|map| map.get_mut(&id.target)) {
+        for info in connections.iter_mut().filter(|info| info.token == id.token) {
+            info.token = token.clone();
+        }
+    }
+
+    if let Err(e) = storage.save_accounts(&card_map) {
         println!("Error in writing card json: {}", e);
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     }
@@ -110,34 +217,131 @@ pub async fn connect_verify(Json(target): Json<TargetVerify>) -> impl IntoRespon
     })).into_response()
 }
 
-pub async fn check_trade_history(Json(id): Json<Identification>) -> impl IntoResponse {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    let day_end = get_day_end(now);
+pub async fn list_connections(State(storage): State<SharedStorage>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let Some(data) = card_map.values().find(|data| data.card_holder == id.card_holder) else {
+        return (StatusCode::BAD_REQUEST, "No card found!").into_response();
+    };
+
+    let mut connections: Vec<_> = data.connection.iter()
+        .flat_map(|map| map.iter())
+        .flat_map(|(platform, targets)| targets.iter().map(move |info| json!({
+            "platform": platform,
+            "token": mask_token(&info.token),
+        })))
+        .collect();
+    connections.sort_by_key(|c| c["platform"].as_str().unwrap_or_default().to_string());
+
+    (StatusCode::OK, Json(json!({ "connections": connections }))).into_response()
+}
 
-    let card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
+pub async fn revoke_connection(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Verified(revoke): Verified<RevokeConnection>) -> impl IntoResponse {
+    let _account = locks.account(&revoke.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&revoke.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
-            return String::from("Server error, please call admin fixing!").into_response();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     };
 
-    let data = match card_map.values().find(|data| data.card_holder == id.card_holder) {
-        Some(card) => card,
-        None => return String::from("No card found!").into_response(),
+    let Some(data) = card_map.values_mut().find(|data| data.card_holder == revoke.card_holder) else {
+        return (StatusCode::BAD_REQUEST, "No card found!").into_response();
+    };
+
+    let removed = data.connection.as_mut().and_then(|map| map.remove(&revoke.platform));
+    if removed.is_none() {
+        return (StatusCode::BAD_REQUEST, "No platform connection record found").into_response();
+    }
+    if data.connection.as_ref().is_some_and(HashMap::is_empty) {
+        data.connection = None;
+    }
+
+    if let Err(e) = storage.save_accounts(&card_map) {
+        println!("Error in writing card json: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (StatusCode::OK, Json(json!({ "status": "ok", "revoked": [revoke.platform] }))).into_response()
+}
+
+pub async fn revoke_all_connections(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let _account = locks.account(&id.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
     };
 
-    let values: Vec<_> = match &data.transaction {
-        Some(map) => map.iter()
-            .filter(|&(&k, _)| k > day_end - 7 * 86400)
-            .map(|(_, v)| *v)
-            .collect(),
-        None => return ("You didn't have any trade!".to_string()).into_response(),
+    let Some(data) = card_map.values_mut().find(|data| data.card_holder == id.card_holder) else {
+        return (StatusCode::BAD_REQUEST, "No card found!").into_response();
     };
 
-    let value_set: HashSet<i64> = values.into_iter().collect();
+    let mut revoked: Vec<String> = data.connection.take()
+        .map(|map| map.into_keys().collect())
+        .unwrap_or_default();
+    revoked.sort();
+
+    if let Err(e) = storage.save_accounts(&card_map) {
+        println!("Error in writing card json: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
 
-    let trade_map: HashMap<i64, TradeHistory> = match get_map("trade.json") {
+    (StatusCode::OK, Json(json!({ "status": "ok", "revoked": revoked }))).into_response()
+}
+
+pub async fn retire_key(State(keys): State<SharedKeys>, Json(retire): Json<RetireKey>) -> impl IntoResponse {
+    if !is_admin(&retire.admin_token) {
+        return (StatusCode::FORBIDDEN, "Admin token required").into_response();
+    }
+
+    let mut keys = keys.write().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = keys.retire(&retire.key_id) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let (active, retired) = keys.key_ids();
+    (StatusCode::OK, Json(json!({
+        "signing": keys.active_id(),
+        "active": active,
+        "retired": retired,
+    }))).into_response()
+}
+
+// read only, the store lock just keeps the snapshot consistent
+pub async fn reconcile_report(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Json(admin): Json<AdminRequest>) -> impl IntoResponse {
+    if !is_admin(&admin.admin_token) {
+        return (StatusCode::FORBIDDEN, "Admin token required").into_response();
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let _store = locks.store().await;
+    match reconcile_storage(storage.as_ref(), now) {
+        Ok(report) => (StatusCode::OK, Json(json!(report))).into_response(),
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response()
+        }
+    }
+}
+
+pub async fn check_trade_history(State(storage): State<SharedStorage>, Verified(query): Verified<TradeHistoryQuery>) -> impl IntoResponse {
+    if let Err(e) = check_history_query(&query) {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(e)).into_response();
+    }
+
+    let card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&query.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -145,16 +349,26 @@ pub async fn check_trade_history(Json(id): Json<Identification>) -> impl IntoRes
         }
     };
 
-    let trades: HashMap<i64, TradeHistory> = trade_map.iter()
-        .filter(|&(&k, _)| value_set.contains(&k))
-        .map(|(&k, v)| (k, v.clone()))
-        .collect();
+    let data = match card_map.values().find(|data| data.card_holder == query.card_holder) {
+        Some(card) => card,
+        None => return String::from("No card found!").into_response(),
+    };
+
+    let trade_ids: Vec<i64> = data.transaction.iter().flat_map(|index| index.iter()).map(|trade| trade.trade_id).collect();
+    let trade_map: HashMap<i64, TradeHistory> = match storage.load_trades_of(&trade_ids) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return String::from("Server error, please call admin fixing!").into_response();
+        }
+    };
 
-    (StatusCode::OK, Json(json!(trades))).into_response()
+    let page = trade_page(data.transaction.as_ref(), &trade_map, &query);
+    (StatusCode::OK, Json(json!(page))).into_response()
 }
 
-pub async fn check_target_exist(Json(id): Json<Identification>) -> impl IntoResponse {
-    let card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
+pub async fn check_target_exist(State(storage): State<SharedStorage>, Json(id): Json<Identification>) -> impl IntoResponse {
+    let card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -169,8 +383,8 @@ pub async fn check_target_exist(Json(id): Json<Identification>) -> impl IntoResp
     }
 }
 
-pub async fn get_balance(Json(id): Json<Identification>) -> impl IntoResponse {
-    let mut card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
+pub async fn get_balance(State(storage): State<SharedStorage>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -186,8 +400,8 @@ pub async fn get_balance(Json(id): Json<Identification>) -> impl IntoResponse {
     (StatusCode::OK, Json(json!({ "balance": data.balance }))).into_response()
 }
 
-pub async fn get_user_card(Json(id): Json<Identification>) -> impl IntoResponse {
-    let mut card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
+pub async fn get_user_card(State(storage): State<SharedStorage>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
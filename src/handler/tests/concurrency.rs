@@ -0,0 +1,94 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use structure::{DiscordTrade, TransactionType, Transfer};
+use function::{account_balances, card_account, check_balances};
+use storage::{open_ledger, IdempotencyStore, SharedIdempotency, SharedLocks};
+use storage::test_util::shared_storage;
+use auth::Verified;
+use handler::{discord_transaction, transfer};
+
+const OPENING: i64 = 10000;
+const DEPOSITS: usize = 200;
+const WITHDRAWALS: usize = 100;
+const TRANSFERS_OUT: usize = 200;
+const TRANSFERS_BACK: usize = 100;
+
+fn dc_trade(card_holder: &str, transaction_type: TransactionType) -> DiscordTrade {
+    DiscordTrade {
+        card_holder: card_holder.to_string(),
+        target_user: String::from("shop"),
+        transaction_type,
+        token: String::new(),
+        target: String::from("discord"),
+        idempotency_key: None,
+    }
+}
+
+fn send(from: &str, to: &str) -> Transfer {
+    Transfer {
+        card_holder: from.to_string(),
+        recipient: to.to_string(),
+        amount: 1.0,
+        token: String::new(),
+        target: String::from("discord"),
+    }
+}
+
+// every request moves 1 USD and they all run at once on the same two cards
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn parallel_transactions_keep_balances_ledger_and_indexes_consistent() {
+    let storage = shared_storage(&[("alice", OPENING), ("bob", OPENING)]);
+    assert_eq!(open_ledger(storage.as_ref(), 0).unwrap(), 2);
+    let locks = SharedLocks::default();
+    let idempotency = SharedIdempotency::new(IdempotencyStore::default());
+
+    let mut tasks = Vec::new();
+    let trades = std::iter::repeat_n(("alice", TransactionType::Credit { amount: 1.0 }), DEPOSITS)
+        .chain(std::iter::repeat_n(("bob", TransactionType::Debit { amount: 1.0 }), WITHDRAWALS));
+    for (card_holder, transaction_type) in trades {
+        let (storage, locks, idempotency) = (storage.clone(), locks.clone(), idempotency.clone());
+        let body = dc_trade(card_holder, transaction_type);
+        tasks.push(tokio::spawn(async move {
+            discord_transaction(State(storage), State(locks), State(idempotency), Verified(body)).await.into_response().status()
+        }));
+    }
+    let transfers = std::iter::repeat_n(("alice", "bob"), TRANSFERS_OUT)
+        .chain(std::iter::repeat_n(("bob", "alice"), TRANSFERS_BACK));
+    for (from, to) in transfers {
+        let (storage, locks) = (storage.clone(), locks.clone());
+        let body = send(from, to);
+        tasks.push(tokio::spawn(async move {
+            transfer(State(storage), State(locks), Verified(body)).await.into_response().status()
+        }));
+    }
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), StatusCode::OK);
+    }
+
+    let card_map = storage.load_accounts().unwrap();
+    let balance = |card_holder: &str| card_map.values().find(|c| c.card_holder == card_holder).unwrap().balance;
+    let alice = OPENING + DEPOSITS as i64 - TRANSFERS_OUT as i64 + TRANSFERS_BACK as i64;
+    let bob = OPENING - WITHDRAWALS as i64 + TRANSFERS_OUT as i64 - TRANSFERS_BACK as i64;
+    assert_eq!(balance("alice"), Decimal::from(alice));
+    assert_eq!(balance("bob"), Decimal::from(bob));
+
+    let ledger = storage.load_ledger().unwrap();
+    assert_eq!(ledger.len(), 2 + DEPOSITS + WITHDRAWALS + TRANSFERS_OUT + TRANSFERS_BACK);
+    assert!(check_balances(&card_map, &ledger).is_empty());
+    let balances = account_balances(&ledger);
+    assert_eq!(balances[&card_account("alice")], Decimal::from(alice));
+    assert!(balances.values().sum::<Decimal>().is_zero());
+
+    // no trade was lost and every card numbers its trades 1, 2, 3, ... without gaps
+    let trade_map = storage.load_trades().unwrap();
+    assert_eq!(trade_map.len(), DEPOSITS + WITHDRAWALS + 2 * (TRANSFERS_OUT + TRANSFERS_BACK));
+    for (card_holder, expected) in [("alice", DEPOSITS + TRANSFERS_OUT + TRANSFERS_BACK), ("bob", WITHDRAWALS + TRANSFERS_OUT + TRANSFERS_BACK)] {
+        let data = card_map.values().find(|c| c.card_holder == card_holder).unwrap();
+        let index: Vec<_> = data.transaction.iter().flat_map(|index| index.iter()).collect();
+        assert_eq!(index.len(), expected);
+        assert!(index.iter().zip(1..).all(|(trade, seq)| trade.seq == seq));
+        assert!(index.iter().all(|trade| trade_map.contains_key(&trade.trade_id)));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::RwLock;
+use axum::body::Body;
+use axum::extract::{FromRef, FromRequest, Json, Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use structure::{AccountAuth, CardInfo, RetireKey, RevokeConnection, TargetVerify};
+use function::{KeyRing, SharedKeys};
+use storage::{SharedLocks, SharedStorage};
+use storage::test_util::{card, json_body, key_ring, shared_storage};
+use auth::Verified;
+use handler::{connect_verify, retire_key, revoke_all_connections, revoke_connection};
+
+#[derive(Clone)]
+struct Bank {
+    card: CardInfo,
+    storage: SharedStorage,
+    locks: SharedLocks,
+    keys: SharedKeys,
+}
+
+fn bank() -> Bank {
+    Bank {
+        storage: shared_storage(&[("alice", 0)]),
+        card: card("alice", 1, 0),
+        locks: SharedLocks::default(),
+        keys: SharedKeys::new(RwLock::new(key_ring(3600))),
+    }
+}
+
+impl FromRef<Bank> for SharedStorage {
+    fn from_ref(bank: &Bank) -> Self {
+        bank.storage.clone()
+    }
+}
+
+impl FromRef<Bank> for SharedKeys {
+    fn from_ref(bank: &Bank) -> Self {
+        bank.keys.clone()
+    }
+}
+
+impl Bank {
+    fn verify(&self) -> TargetVerify {
+        TargetVerify {
+            card_holder: self.card.card_holder.clone(),
+            target: String::from("discord"),
+            card_number: self.card.card_number.clone(),
+            good_thru: self.card.good_thru.clone(),
+            verify_number: self.card.verify_number.clone(),
+        }
+    }
+
+    async fn connect(&self, body: TargetVerify) -> Response {
+        connect_verify(State(self.storage.clone()), State(self.locks.clone()), State(self.keys.clone()), Json(body))
+            .await
+            .into_response()
+    }
+
+    async fn token(&self) -> String {
+        let connected = json_body(self.connect(self.verify()).await).await;
+        assert_eq!(connected["status"], "ok");
+        connected["token"].as_str().unwrap().to_string()
+    }
+
+    fn auth(&self, token: &str) -> AccountAuth {
+        AccountAuth { card_holder: self.card.card_holder.clone(), token: token.to_string(), target: String::from("discord") }
+    }
+
+    // what the token check in front of every authenticated route answers for `token`
+    async fn check(&self, token: &str) -> StatusCode {
+        let request = Request::builder()
+            .method("POST")
+            .header("content-type", "application/json")
+            .body(Body::from(json!(self.auth(token)).to_string()))
+            .unwrap();
+        match Verified::<AccountAuth>::from_request(request, self).await {
+            Ok(_) => StatusCode::OK,
+            Err(response) => response.status(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn connect_needs_the_card_details() {
+    let bank = bank();
+    let body = TargetVerify { verify_number: String::from("000"), ..bank.verify() };
+
+    assert_eq!(bank.connect(body).await.status(), StatusCode::UNAUTHORIZED);
+    assert!(bank.storage.load_accounts().unwrap()[&1].connection.is_none());
+}
+
+#[tokio::test]
+async fn an_existing_connection_never_gets_its_token_back() {
+    let bank = bank();
+
+    let first = json_body(bank.connect(bank.verify()).await).await;
+    assert_eq!(first["status"], "ok");
+    assert!(first["token"].as_str().is_some_and(|token| token.starts_with("k1.")));
+
+    let second = json_body(bank.connect(bank.verify()).await).await;
+    assert_eq!(second["status"], "exists");
+    assert!(second.get("token").is_none());
+}
+
+#[tokio::test]
+async fn revoked_token_is_refused_until_connecting_again() {
+    let bank = bank();
+    let token = bank.token().await;
+    assert_eq!(bank.check(&token).await, StatusCode::OK);
+
+    let revoke = RevokeConnection {
+        card_holder: bank.card.card_holder.clone(),
+        token: token.clone(),
+        target: String::from("discord"),
+        platform: String::from("discord"),
+    };
+    let response = revoke_connection(State(bank.storage.clone()), State(bank.locks.clone()), Verified(revoke)).await.into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(bank.check(&token).await, StatusCode::UNAUTHORIZED);
+
+    let token = bank.token().await;
+    assert_eq!(bank.check(&token).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn revoking_every_connection_refuses_the_token() {
+    let bank = bank();
+    let token = bank.token().await;
+
+    let response = revoke_all_connections(State(bank.storage.clone()), State(bank.locks.clone()), Verified(bank.auth(&token))).await.into_response();
+    assert_eq!(json_body(response).await["revoked"], json!(["discord"]));
+    assert_eq!(bank.check(&token).await, StatusCode::UNAUTHORIZED);
+    assert!(bank.storage.load_accounts().unwrap()[&1].connection.is_none());
+
+    let token = bank.token().await;
+    assert_eq!(bank.check(&token).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn token_of_a_retired_key_is_refused_and_connect_signs_a_new_one() {
+    let bank = bank();
+    let old = bank.token().await;
+
+    // rotated to k2, k1 still verifies until it is retired
+    let retired_path = env::temp_dir().join(format!("retired_keys_{}.json", std::process::id()));
+    let keys = HashMap::from([(String::from("k1"), String::from("secret")), (String::from("k2"), String::from("other"))]);
+    *bank.keys.write().unwrap() = KeyRing::new(keys, String::from("k2"), HashSet::new(), 3600).unwrap()
+        .with_retired_path(retired_path.to_str().unwrap());
+    assert_eq!(bank.check(&old).await, StatusCode::OK);
+
+    // SAFETY: no other test in this binary reads ADMIN_TOKEN
+    unsafe { env::set_var("ADMIN_TOKEN", "admin") };
+    let retire = RetireKey { admin_token: String::from("admin"), key_id: String::from("k1") };
+    let response = retire_key(State(bank.keys.clone()), Json(retire)).await.into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(bank.check(&old).await, StatusCode::UNAUTHORIZED);
+
+    let new = bank.token().await;
+    assert!(new.starts_with("k2."));
+    assert_eq!(bank.check(&new).await, StatusCode::OK);
+    std::fs::remove_file(retired_path).unwrap();
+}
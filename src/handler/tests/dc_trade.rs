@@ -0,0 +1,32 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use structure::{DiscordTrade, TransactionType};
+use storage::{IdempotencyStore, SharedIdempotency, SharedLocks};
+use storage::test_util::shared_storage;
+use auth::Verified;
+use handler::discord_transaction;
+
+#[tokio::test]
+async fn negative_deposits_and_withdrawals_are_bad_requests() {
+    let storage = shared_storage(&[("alice", 20)]);
+
+    for transaction_type in [TransactionType::Credit { amount: -5.0 }, TransactionType::Debit { amount: -5.0 }] {
+        let body = DiscordTrade {
+            card_holder: String::from("alice"),
+            target_user: String::from("shop"),
+            transaction_type,
+            token: String::new(),
+            target: String::from("discord"),
+            idempotency_key: None,
+        };
+        let response = discord_transaction(State(storage.clone()), State(SharedLocks::default()), State(SharedIdempotency::new(IdempotencyStore::default())), Verified(body))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    assert_eq!(storage.load_accounts().unwrap()[&1].balance, Decimal::from(20));
+    assert!(storage.load_trades().unwrap().is_empty());
+}
@@ -0,0 +1,31 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use structure::{TradeHistoryQuery, TradeSort};
+use storage::test_util::shared_storage;
+use auth::Verified;
+use handler::check_trade_history;
+
+#[tokio::test]
+async fn range_that_ends_before_it_starts_is_unprocessable() {
+    let storage = shared_storage(&[("alice", 0)]);
+
+    for (from, to) in [(1000, 1000), (2000, 1000)] {
+        let query = TradeHistoryQuery {
+            card_holder: String::from("alice"),
+            token: String::new(),
+            target: String::from("discord"),
+            from: Some(from),
+            to: Some(to),
+            action: None,
+            counterparty: None,
+            min_amount: None,
+            max_amount: None,
+            sort: TradeSort::Newest,
+            cursor: None,
+            limit: None,
+        };
+        let response = check_trade_history(State(storage.clone()), Verified(query)).await.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
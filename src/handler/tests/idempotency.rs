@@ -0,0 +1,50 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use structure::{DiscordTrade, TransactionType};
+use storage::{IdempotencyStore, SharedIdempotency, SharedLocks};
+use storage::test_util::{body_text, shared_storage};
+use auth::Verified;
+use handler::discord_transaction;
+
+fn deposit(key: &str) -> DiscordTrade {
+    DiscordTrade {
+        card_holder: String::from("alice"),
+        target_user: String::from("shop"),
+        transaction_type: TransactionType::Credit { amount: 5.0 },
+        token: String::new(),
+        target: String::from("discord"),
+        idempotency_key: Some(key.to_string()),
+    }
+}
+
+#[tokio::test]
+async fn dc_trade_retried_with_the_same_key_is_applied_once() {
+    let storage = shared_storage(&[("alice", 0)]);
+    let locks = SharedLocks::default();
+    let send = |idempotency: SharedIdempotency, key: &str| {
+        discord_transaction(State(storage.clone()), State(locks.clone()), State(idempotency), Verified(deposit(key)))
+    };
+
+    let first = send(SharedIdempotency::new(IdempotencyStore::default()), "k1").await.into_response();
+    assert_eq!(first.status(), StatusCode::OK);
+    assert!(first.headers().get("idempotent-replay").is_none());
+    let first = body_text(first).await;
+
+    // a fresh store is what the server has after a restart
+    let retry = send(SharedIdempotency::new(IdempotencyStore::default()), "k1").await.into_response();
+    assert_eq!(retry.status(), StatusCode::OK);
+    assert_eq!(retry.headers()["idempotent-replay"], "true");
+    assert_eq!(body_text(retry).await, first);
+
+    let balance = || storage.load_accounts().unwrap()[&1].balance;
+    assert_eq!(balance(), Decimal::from(5));
+    assert_eq!(storage.load_trades().unwrap().len(), 1);
+
+    // another key is another deposit
+    let other = send(SharedIdempotency::new(IdempotencyStore::default()), "k2").await.into_response();
+    assert_eq!(other.status(), StatusCode::OK);
+    assert_eq!(balance(), Decimal::from(10));
+    assert_eq!(storage.load_trades().unwrap().len(), 2);
+}
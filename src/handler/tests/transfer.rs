@@ -0,0 +1,85 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use rust_decimal::Decimal;
+use structure::{Transfer, TransactionType};
+use function::{card_account, check_balances};
+use storage::{open_ledger, SharedLocks, SharedStorage};
+use storage::test_util::shared_storage;
+use auth::Verified;
+use handler::transfer;
+
+// alice holds 100 and bob nothing, the ledger opened at those balances
+fn bank() -> SharedStorage {
+    let storage = shared_storage(&[("alice", 100), ("bob", 0)]);
+    open_ledger(storage.as_ref(), 0).unwrap();
+    storage
+}
+
+async fn send(storage: &SharedStorage, card_holder: &str, recipient: &str, amount: f64) -> Response {
+    let body = Transfer {
+        card_holder: card_holder.to_string(),
+        recipient: recipient.to_string(),
+        amount,
+        token: String::new(),
+        target: String::from("discord"),
+    };
+    transfer(State(storage.clone()), State(SharedLocks::default()), Verified(body)).await.into_response()
+}
+
+fn balances(storage: &SharedStorage) -> (Decimal, Decimal) {
+    let card_map = storage.load_accounts().unwrap();
+    (card_map[&1].balance, card_map[&2].balance)
+}
+
+#[tokio::test]
+async fn refused_transfers_move_no_money() {
+    let storage = bank();
+
+    for (card_holder, recipient, amount) in [
+        ("alice", "alice", 10.0),
+        ("alice", "carol", 10.0),
+        ("alice", "bob", 0.0),
+        ("alice", "bob", -10.0),
+        ("alice", "bob", 100.5),
+    ] {
+        let response = send(&storage, card_holder, recipient, amount).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{} -> {} {}", card_holder, recipient, amount);
+    }
+
+    assert_eq!(balances(&storage), (Decimal::from(100), Decimal::ZERO));
+    assert!(storage.load_trades().unwrap().is_empty());
+    assert_eq!(storage.load_ledger().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn transfer_writes_two_linked_trades_and_one_balanced_entry() {
+    let storage = bank();
+    let bob_number = storage.load_accounts().unwrap()[&2].card_number.clone();
+
+    let response = send(&storage, "alice", &bob_number, 40.0).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(balances(&storage), (Decimal::from(60), Decimal::from(40)));
+
+    let trades = storage.load_trades().unwrap();
+    assert_eq!(trades.len(), 2);
+    let (debit_id, debit) = trades.iter().find(|(_, t)| matches!(t.transaction_type, TransactionType::Debit { .. })).unwrap();
+    let (credit_id, credit) = trades.iter().find(|(_, t)| matches!(t.transaction_type, TransactionType::Credit { .. })).unwrap();
+    assert_eq!(debit.linked_trade, Some(*credit_id));
+    assert_eq!(credit.linked_trade, Some(*debit_id));
+    assert_eq!(debit.target_user, "bob");
+    assert_eq!(credit.target_user, "alice");
+
+    let card_map = storage.load_accounts().unwrap();
+    assert_eq!(card_map[&1].transaction.as_ref().unwrap().first_at(debit.timestamp), Some(*debit_id));
+    assert_eq!(card_map[&2].transaction.as_ref().unwrap().first_at(credit.timestamp), Some(*credit_id));
+
+    let ledger = storage.load_ledger().unwrap();
+    let entries: Vec<_> = ledger.values().filter(|entry| entry.trade_id.is_some()).collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].trade_id, Some(*debit_id));
+    assert_eq!(entries[0].postings.iter().map(|p| p.amount).sum::<Decimal>(), Decimal::ZERO);
+    assert!(entries[0].postings.iter().any(|p| p.account == card_account("alice") && p.amount == Decimal::from(-40)));
+    assert!(entries[0].postings.iter().any(|p| p.account == card_account("bob") && p.amount == Decimal::from(40)));
+    assert!(check_balances(&card_map, &ledger).is_empty());
+}
@@ -1,9 +1,22 @@
-use axum::{routing::post, Router};
+use axum::{extract::FromRef, routing::post, Router};
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tower_http::cors::{Any, CorsLayer};
-use handler::{sign_up_discord, connect_verify, check_target_exist, discord_transaction, get_balance, check_trade_history, get_user_card};
-use stock::{get_last_price, buy_stock, sell_stock, check_stock_hold, get_stock_history};
+use handler::{sign_up_discord, connect_verify, refresh_token, list_connections, revoke_connection, revoke_all_connections, retire_key, reconcile_report, check_target_exist, discord_transaction, transfer, get_balance, check_trade_history, get_user_card};
+use stock::{get_last_price, buy_stock, sell_stock, check_stock_hold, get_stock_history, place_order, list_orders, cancel_order, run_order_matching, match_interval_from_env, set_triggers, run_trigger_watcher, trigger_interval_from_env, get_margin_status, get_portfolio, get_trading_stats, get_leaderboard, set_leaderboard_opt_out, run_margin_monitor, margin_interval_from_env, SharedMargin, provider_from_env, PriceCache, SharedQuotes};
+use function::{KeyRing, SharedKeys};
+use storage::{import_storage, open_ledger, reconcile_storage, IdempotencyStore, JsonStorage, Storage, StorageConfig, SharedIdempotency, SharedLocks, SharedStorage};
+
+#[derive(Clone, FromRef)]
+struct AppState {
+    storage: SharedStorage,
+    locks: SharedLocks,
+    keys: SharedKeys,
+    idempotency: SharedIdempotency,
+    quotes: SharedQuotes,
+    margin: SharedMargin,
+}
 
 #[tokio::main]
 async fn main() {
@@ -13,20 +26,115 @@ async fn main() {
         .allow_headers(Any)
         .max_age(Duration::from_secs(60 * 60));
 
+    let config = StorageConfig::from_env().unwrap();
+    let storage: SharedStorage = config.open().unwrap();
+
+    // `cargo run -- import-json` copies the existing json files into the configured backend
+    if std::env::args().nth(1).as_deref() == Some("import-json") {
+        // a crash may have left journal.json behind, finish that update before copying
+        let source = JsonStorage::default();
+        if let Err(e) = source.recover() {
+            eprintln!("Import failed： {}", e);
+            return;
+        }
+        match import_storage(&source, storage.as_ref()) {
+            Ok((cards, trades, holds, orders, closed, entries)) => println!("Imported {} cards, {} trades, {} stock holds, {} orders, {} closed positions, {} ledger entries", cards, trades, holds, orders, closed, entries),
+            Err(e) => eprintln!("Import failed： {}", e),
+        }
+        return;
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+    // `cargo run -- reconcile` prints the discrepancy report as JSON, exiting with 1 if there is any
+    if std::env::args().nth(1).as_deref() == Some("reconcile") {
+        match reconcile_storage(storage.as_ref(), now) {
+            Ok(report) => {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                if !report.discrepancies.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("Reconciliation failed： {}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
+    let opened = open_ledger(storage.as_ref(), now).unwrap();
+    if opened > 0 {
+        println!("Posted opening ledger balances for {} cards", opened);
+    }
+
+    // responses past the idempotency window are never replayed again
+    let idempotency = IdempotencyStore::from_env().unwrap();
+    let pruned = storage.prune_responses(idempotency.expired_before()).unwrap();
+    if pruned > 0 {
+        println!("Removed {} expired idempotent responses", pruned);
+    }
+
+    let state = AppState {
+        storage,
+        locks: SharedLocks::default(),
+        keys: SharedKeys::new(RwLock::new(KeyRing::from_env().unwrap())),
+        idempotency: SharedIdempotency::new(idempotency),
+        quotes: SharedQuotes::new(PriceCache::from_env(provider_from_env().unwrap()).unwrap()),
+        margin: SharedMargin::default(),
+    };
+
+    tokio::spawn(run_order_matching(
+        state.storage.clone(),
+        state.locks.clone(),
+        state.quotes.clone(),
+        match_interval_from_env().unwrap(),
+    ));
+    tokio::spawn(run_trigger_watcher(
+        state.storage.clone(),
+        state.locks.clone(),
+        state.quotes.clone(),
+        trigger_interval_from_env().unwrap(),
+    ));
+    tokio::spawn(run_margin_monitor(
+        state.storage.clone(),
+        state.locks.clone(),
+        state.quotes.clone(),
+        state.margin.clone(),
+        margin_interval_from_env().unwrap(),
+    ));
+
     let app = Router::new()
         .route("/signup", post(sign_up_discord))
         .route("/get_balance", post(get_balance))
         .route("/get_price", post(get_last_price))
         .route("/get_card", post(get_user_card))
         .route("/dc_trade", post(discord_transaction))
+        .route("/transfer", post(transfer))
         .route("/connect", post(connect_verify))
+        .route("/refresh_token", post(refresh_token))
+        .route("/connections", post(list_connections))
+        .route("/revoke_connection", post(revoke_connection))
+        .route("/revoke_all", post(revoke_all_connections))
         .route("/buy_stock", post(buy_stock))
         .route("/stock_history", post(get_stock_history))
         .route("/check_stock", post(check_stock_hold))
+        .route("/portfolio", post(get_portfolio))
+        .route("/stats", post(get_trading_stats))
+        .route("/leaderboard", post(get_leaderboard))
+        .route("/leaderboard_opt_out", post(set_leaderboard_opt_out))
         .route("/check_trade", post(check_trade_history))
         .route("/sell_stock", post(sell_stock))
+        .route("/place_order", post(place_order))
+        .route("/orders", post(list_orders))
+        .route("/cancel_order", post(cancel_order))
+        .route("/set_triggers", post(set_triggers))
+        .route("/margin_status", post(get_margin_status))
         .route("/check_target", post(check_target_exist))
-        .layer(cors);
+        .route("/admin/retire_key", post(retire_key))
+        .route("/admin/reconcile", post(reconcile_report))
+        .layer(cors)
+        .with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     println!("Server successfully run at {}", addr);
@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task;
+use crate::provider::{PriceError, SharedPrices};
+
+pub type SharedQuotes = Arc<PriceCache>;
+
+#[derive(Serialize, Clone)]
+pub struct PriceQuote {
+    pub symbol: String,
+    pub price: Decimal,
+    // unix seconds when the price was fetched from the provider
+    pub quoted_at: i64,
+}
+
+struct CachedPrice {
+    quote: PriceQuote,
+    fetched: Instant,
+}
+
+// shared by every handler: resolved symbols and last prices with a fresh window,
+// then a stale window where the old price is served while a refresh runs in the background
+pub struct PriceCache {
+    provider: SharedPrices,
+    symbol_ttl: Duration,
+    price_ttl: Duration,
+    stale_ttl: Duration,
+    symbols: Mutex<HashMap<String, (String, Instant)>>,
+    prices: Mutex<HashMap<String, CachedPrice>>,
+    refreshing: Mutex<HashSet<String>>,
+    fetching: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+pub(crate) fn env_secs(name: &str, default: u64) -> Result<Duration, String> {
+    match env::var(name) {
+        Ok(secs) => secs.parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|e| format!("Invalid {} {} ：{}", name, secs, e)),
+        Err(_) => Ok(Duration::from_secs(default)),
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+impl PriceCache {
+    pub fn new(provider: SharedPrices, symbol_ttl: Duration, price_ttl: Duration, stale_ttl: Duration) -> Self {
+        PriceCache {
+            provider,
+            symbol_ttl,
+            price_ttl,
+            stale_ttl,
+            symbols: Mutex::new(HashMap::new()),
+            prices: Mutex::new(HashMap::new()),
+            refreshing: Mutex::new(HashSet::new()),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // SYMBOL_TTL (default 1 day), QUOTE_TTL (default 60s) and QUOTE_STALE (default 300s), all in seconds
+    pub fn from_env(provider: SharedPrices) -> Result<Self, String> {
+        Ok(PriceCache::new(
+            provider,
+            env_secs("SYMBOL_TTL", 86400)?,
+            env_secs("QUOTE_TTL", 60)?,
+            env_secs("QUOTE_STALE", 300)?,
+        ))
+    }
+
+    pub fn provider(&self) -> &SharedPrices {
+        &self.provider
+    }
+
+    pub async fn symbol(&self, name: &str) -> Result<String, PriceError> {
+        let key = name.to_lowercase();
+        {
+            let symbols = self.symbols.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((symbol, resolved)) = symbols.get(&key)
+                && resolved.elapsed() < self.symbol_ttl {
+                return Ok(symbol.clone());
+            }
+        }
+
+        let provider = self.provider.clone();
+        let name = name.to_string();
+        let symbol = task::spawn_blocking(move || provider.search_symbol(&name))
+            .await
+            .map_err(|e| PriceError::Unavailable(e.to_string()))??;
+
+        let mut symbols = self.symbols.lock().unwrap_or_else(|e| e.into_inner());
+        symbols.insert(key, (symbol.clone(), Instant::now()));
+        Ok(symbol)
+    }
+
+    pub async fn quote(self: &Arc<Self>, name: &str) -> Result<PriceQuote, PriceError> {
+        let symbol = self.symbol(name).await?;
+        self.quote_symbol(&symbol).await
+    }
+
+    // price of a ticker that was already resolved, skipping the symbol lookup
+    pub async fn quote_symbol(self: &Arc<Self>, symbol: &str) -> Result<PriceQuote, PriceError> {
+        let symbol = symbol.to_string();
+        match self.cached(&symbol) {
+            Some((quote, age)) if age < self.price_ttl => Ok(quote),
+            Some((quote, age)) if age < self.price_ttl + self.stale_ttl => {
+                self.revalidate(symbol);
+                Ok(quote)
+            }
+            _ => self.fetch_once(symbol).await,
+        }
+    }
+
+    fn cached(&self, symbol: &str) -> Option<(PriceQuote, Duration)> {
+        let prices = self.prices.lock().unwrap_or_else(|e| e.into_inner());
+        prices.get(symbol).map(|c| (c.quote.clone(), c.fetched.elapsed()))
+    }
+
+    // concurrent misses for the same symbol wait for the first one instead of each asking the provider
+    async fn fetch_once(&self, symbol: String) -> Result<PriceQuote, PriceError> {
+        let gate = {
+            let mut fetching = self.fetching.lock().unwrap_or_else(|e| e.into_inner());
+            fetching.entry(symbol.clone()).or_default().clone()
+        };
+        let _fetching = gate.lock().await;
+
+        // fetched by another request while this one waited
+        if let Some((quote, age)) = self.cached(&symbol)
+            && age < self.price_ttl {
+            return Ok(quote);
+        }
+
+        let result = self.fetch(symbol.clone()).await;
+        self.fetching.lock().unwrap_or_else(|e| e.into_inner()).remove(&symbol);
+        result
+    }
+
+    async fn fetch(&self, symbol: String) -> Result<PriceQuote, PriceError> {
+        let provider = self.provider.clone();
+        let lookup = symbol.clone();
+        let price = task::spawn_blocking(move || provider.latest_price(&lookup))
+            .await
+            .map_err(|e| PriceError::Unavailable(e.to_string()))??;
+
+        let quote = PriceQuote { symbol: symbol.clone(), price, quoted_at: unix_now() };
+        let mut prices = self.prices.lock().unwrap_or_else(|e| e.into_inner());
+        prices.insert(symbol, CachedPrice { quote: quote.clone(), fetched: Instant::now() });
+        Ok(quote)
+    }
+
+    fn revalidate(self: &Arc<Self>, symbol: String) {
+        {
+            let mut refreshing = self.refreshing.lock().unwrap_or_else(|e| e.into_inner());
+            if !refreshing.insert(symbol.clone()) {
+                return;
+            }
+        }
+
+        let cache = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cache.fetch(symbol.clone()).await {
+                println!("Failed to refresh price of {}: {}", symbol, e);
+            }
+            cache.refreshing.lock().unwrap_or_else(|e| e.into_inner()).remove(&symbol);
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use axum::{extract::{Json, State}, response::IntoResponse};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::json;
+use structure::{CardInfo, ClosedPosition, Leaderboard, LeaderboardOptOut, Leverage, StockHold};
+use function::get_day_end;
+use storage::{Changes, SharedLocks, SharedStorage};
+use auth::Verified;
+use crate::{position_cost, quote_symbols, value_position, SharedQuotes};
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub card_holder: String,
+    pub value: Decimal,
+    // positions closed in the period
+    pub trades: usize,
+}
+
+// start of the period as a unix time, days end at local midnight like /check_trade
+fn period_start(period: &str, now: i64) -> Result<i64, String> {
+    let day_end = get_day_end(now);
+    match period {
+        "day" => Ok(day_end - 86400),
+        "week" => Ok(day_end - 7 * 86400),
+        "all" => Ok(i64::MIN),
+        other => Err(format!("Unknown period {}, use day, week or all", other)),
+    }
+}
+
+// higher value first, then fewer trades for the same result, then card holder so the order never flips
+fn rank(mut entries: Vec<LeaderboardEntry>, limit: usize) -> Vec<LeaderboardEntry> {
+    entries.sort_by(|a, b| {
+        b.value.cmp(&a.value)
+            .then(a.trades.cmp(&b.trades))
+            .then_with(|| a.card_holder.cmp(&b.card_holder))
+    });
+    entries.truncate(limit);
+    for (i, entry) in entries.iter_mut().enumerate() {
+        entry.rank = i + 1;
+    }
+    entries
+}
+
+pub async fn get_leaderboard(State(storage): State<SharedStorage>, State(quotes): State<SharedQuotes>, Json(board): Json<Leaderboard>) -> impl IntoResponse {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let since = match period_start(&board.period, now) {
+        Ok(since) => since,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let limit = board.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let card_map: HashMap<u64, CardInfo> = match storage.load_accounts() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let closed_map: HashMap<String, Vec<ClosedPosition>> = match storage.load_closed() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let cards: Vec<&CardInfo> = card_map.values().filter(|card| !card.leaderboard_opt_out).collect();
+    let closed_in_period = |card_holder: &str| -> Vec<&ClosedPosition> {
+        closed_map.get(card_holder)
+            .map(|closed| closed.iter().filter(|c| c.closed_at >= since).collect())
+            .unwrap_or_default()
+    };
+
+    let entries: Vec<LeaderboardEntry> = match board.metric.as_str() {
+        "equity" => {
+            let stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings() {
+                Ok(map) => map,
+                Err(e) => {
+                    eprintln!("Error： {}", e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+                }
+            };
+            // open buy orders hold their funds outside the balance until they fill or are cancelled
+            let mut reserved: HashMap<String, Decimal> = HashMap::new();
+            match storage.load_open_orders() {
+                Ok(map) => for order in map.into_values() {
+                    *reserved.entry(order.card_holder).or_default() += order.reserved;
+                },
+                Err(e) => {
+                    eprintln!("Error： {}", e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+                }
+            }
+            let symbols = stock_map.values().flatten().map(|hold| hold.stock.symbol.clone()).collect();
+            let prices = quote_symbols(&quotes, symbols).await;
+
+            cards.iter().map(|card| {
+                let positions: Decimal = stock_map.get(&card.card_holder).into_iter().flatten()
+                    .map(|hold| {
+                        // a symbol without a price counts at what it cost
+                        let price = prices.get(&hold.stock.symbol).copied().unwrap_or(hold.stock.price);
+                        value_position(hold, price).map(|p| p.value).unwrap_or(Decimal::ZERO)
+                    })
+                    .sum();
+                LeaderboardEntry {
+                    rank: 0,
+                    card_holder: card.card_holder.clone(),
+                    value: card.balance + reserved.get(&card.card_holder).copied().unwrap_or_default() + positions,
+                    trades: closed_in_period(&card.card_holder).len(),
+                }
+            }).collect()
+        }
+        "realized_pnl" | "return" => cards.iter().filter_map(|card| {
+            let closed = closed_in_period(&card.card_holder);
+            if closed.is_empty() {
+                return None;
+            }
+            let pnl: Decimal = closed.iter().map(|c| c.earning).sum();
+            let value = if board.metric == "return" {
+                // realized profit against the principal put into those positions, in percent
+                let invested: Decimal = closed.iter()
+                    .filter_map(|c| Leverage::try_from(c.leverage).ok().map(|leverage| position_cost(c.entry_price, c.hand, leverage)))
+                    .sum();
+                if invested <= Decimal::ZERO {
+                    return None;
+                }
+                (pnl / invested * Decimal::ONE_HUNDRED).round_dp(2)
+            } else {
+                pnl
+            };
+            Some(LeaderboardEntry {
+                rank: 0,
+                card_holder: card.card_holder.clone(),
+                value,
+                trades: closed.len(),
+            })
+        }).collect(),
+        other => return (StatusCode::BAD_REQUEST, format!("Unknown metric {}, use equity, realized_pnl or return", other)).into_response(),
+    };
+
+    (StatusCode::OK, Json(json!({
+        "metric": board.metric,
+        "period": board.period,
+        "entries": rank(entries, limit)
+    }))).into_response()
+}
+
+pub async fn set_leaderboard_opt_out(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Verified(opt): Verified<LeaderboardOptOut>) -> impl IntoResponse {
+    let _account = locks.account(&opt.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&opt.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let Some(data) = card_map.values_mut().find(|data| data.card_holder == opt.card_holder) else {
+        return (StatusCode::BAD_REQUEST, "No card holder found").into_response();
+    };
+    data.leaderboard_opt_out = opt.opt_out;
+
+    let changes = Changes { accounts: Some(&card_map), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing leaderboard setting: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+
+    (StatusCode::OK, Json(json!({ "opt_out": opt.opt_out }))).into_response()
+}
@@ -1,36 +1,63 @@
-use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
-use axum::{extract::Json, response::IntoResponse};
+mod cache;
+mod leaderboard;
+mod limits;
+mod margin;
+mod orders;
+mod portfolio;
+mod provider;
+mod stats;
+mod triggers;
+
+use std::collections::{HashMap, HashSet};
+use axum::{extract::{Json, State}, response::IntoResponse};
 use axum::http::StatusCode;
-use rust_decimal::{Decimal, prelude::FromPrimitive};
-use structure::{CardInfo, BuyStock, Symbol, Stock, SellStock, TradeHistory, TransactionType, StockHold, Identification, StockHistory};
-use function::{check_balance, write_json_to_file, get_map};
-use yahoo_finance_api as yahoo;
-use std::error::Error as StdError;
+use rust_decimal::Decimal;
+use structure::{AccountAuth, CardInfo, ClosedPosition, BuyStock, LedgerEntry, Leverage, Side, Symbol, Stock, SellStock, TradeHistory, TradeIndex, TransactionType, StockHold, StockHistory};
+use function::{check_balance, post_card, IdSource, Sequence, STOCK_CLEARING};
+use storage::{Changes, IdempotencyStore, SharedIdempotency, SharedLocks, SharedStorage};
+use auth::Verified;
 use std::time::{SystemTime, UNIX_EPOCH};
 use rust_decimal::prelude::ToPrimitive;
 use serde_json::json;
 use tokio::task;
 use yahoo_finance_api::Quote;
 
-pub async fn buy_stock(Json(stock): Json<BuyStock>) -> impl IntoResponse {
-    let mut card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
-        Ok(map) => map,
+pub use cache::{PriceCache, PriceQuote, SharedQuotes};
+pub use leaderboard::{get_leaderboard, set_leaderboard_opt_out, LeaderboardEntry};
+pub use limits::{card_limits, check_purchase, CardLimits};
+pub use margin::{get_margin_status, margin_interval_from_env, margin_status, margin_tier, mark_to_market, run_margin_monitor, MarginMonitor, MarginStatus, MarginTier, SharedMargin, MAX_LEVERAGE};
+pub use orders::{cancel_order, list_orders, match_interval_from_env, match_orders, place_order, run_order_matching};
+pub use stats::{get_trading_stats, trading_stats, TradingStats};
+pub use triggers::{close_triggered_positions, run_trigger_watcher, set_triggers, trigger_interval_from_env};
+pub use portfolio::{get_portfolio, value_position, PositionValue};
+pub use provider::{provider_from_env, Fixture, PriceError, PriceProvider, ScriptedProvider, SharedPrices, YahooProvider};
+
+pub async fn buy_stock(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(idempotency): State<SharedIdempotency>, State(quotes): State<SharedQuotes>, Verified(stock): Verified<BuyStock>) -> impl IntoResponse {
+    let _account = locks.account(&stock.card_holder).await;
+    let replay_key = IdempotencyStore::key("buy_stock", &stock.card_holder, stock.idempotency_key.as_deref());
+    if let Some(response) = idempotency.replay(storage.as_ref(), replay_key.as_deref()) {
+        return response;
+    }
+
+    // fetched before taking the store lock so a slow quote doesn't block other accounts
+    let quote = match get_stock_price(&quotes, stock.symbol.as_str()).await {
+        Ok(q) => q,
         Err(e) => {
-            eprintln!("Error： {}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            println!("Failed to get price: {}", e);
+            return (price_error_status(&e), "Failed to get price!").into_response();
         }
     };
 
-    let mut stock_map: HashMap<String, Vec<StockHold>> = match get_map("stockhold.json") {
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&stock.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     };
 
-    let mut trade_map: HashMap<i64, TradeHistory> = match get_map("trade.json") {
+    let mut stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings_of(&stock.card_holder) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -38,113 +65,100 @@ pub async fn buy_stock(Json(stock): Json<BuyStock>) -> impl IntoResponse {
         }
     };
 
-    let data = match get_verified_card(&mut card_map, &stock.card_holder, &stock.target, &stock.token) {
-        Ok(d) => d,
-        Err(e) => return e.into_response(),
-    };
+    let mut trade_map: HashMap<i64, TradeHistory> = HashMap::new();
+    let mut ledger: HashMap<i64, LedgerEntry> = HashMap::new();
 
-    let price = match get_stock_price(stock.symbol.as_str()).await {
-        Ok(p) => p,
-        Err(e) => {
-            println!("Failed to get price: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get price!").into_response();
-        }
+    let data = match card_map.values_mut().find(|data| data.card_holder == stock.card_holder) {
+        Some(card) => card,
+        None => return (StatusCode::BAD_REQUEST, "No card holder found").into_response(),
     };
 
-    let last_trade: i64;
-
-    if trade_map.is_empty() {
-        last_trade = 0;
-    } else {
-        let Some(last) = trade_map.keys().max() else { todo!() };
-        last_trade = *last;
-    };
+    if let Err(e) = check_purchase(&data.card_type, stock.hand, stock.leverage) {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(e)).into_response();
+    }
+    let price = quote.price;
+    if let Err(e) = check_triggers(stock.buy_type, price, stock.stop_loss, stock.take_profit) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
 
-    let total_cost = price * Decimal::from(stock.hand) / Decimal::new(stock.leverage.to_i64().unwrap(), 2);
+    let total_cost = position_cost(price, stock.hand.get(), stock.leverage);
 
     if !check_balance(&data.balance, total_cost) {
         return (StatusCode::BAD_REQUEST, "Insufficient balance").into_response();
     }
 
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    data.balance -= total_cost;
-    let transaction_map = data.transaction.get_or_insert_with(HashMap::new);
-    transaction_map.insert(now, last_trade + 1);
-
-    if let Err(e) = write_json_to_file("account.json", &card_map) {
-        println!("Error in writing card json: {}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    if let Err(e) = record_stock_trade(data, &mut trade_map, &mut ledger, storage.as_ref(), now, -total_cost, &format!("buy {}", stock.symbol), None) {
+        eprintln!("Error： {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
     }
 
-    let stock_info = stock_map.entry(stock.card_holder.clone()).or_insert_with(Vec::new);
+    let stock_info = stock_map.entry(stock.card_holder.clone()).or_default();
     stock_info.push(StockHold {
         timestamp: now,
         stock: Stock {
             buy_type: stock.buy_type,
             symbol: stock.symbol.clone(),
-            hand: stock.hand,
-            leverage: stock.leverage,
+            hand: stock.hand.get(),
+            leverage: stock.leverage.get(),
             price,
         },
+        stop_loss: stock.stop_loss,
+        take_profit: stock.take_profit,
     });
 
-    if let Err(e) = write_json_to_file("stockhold.json", &stock_map) {
-        println!("Error in writing trade json: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
-    }
-
-    let trade_info = TradeHistory {
-        timestamp: now,
-        transaction_type: TransactionType::Debit { amount: total_cost.to_f64().unwrap() },
-        target_user: String::from("Stock! Bot"),
+    let response = (StatusCode::OK, Json(json!({
+        "symbol": stock.symbol,
+        "hand": stock.hand,
+        "leverage": stock.leverage,
+        "cost": total_cost,
+        "price": price,
+        "quoted_at": quote.quoted_at
+    }))).into_response();
+    let (responses, response) = match idempotency.capture(replay_key, response).await {
+        Ok(captured) => captured,
+        Err(response) => return response,
     };
 
-    trade_map.insert(last_trade + 1, trade_info);
-
-    if let Err(e) = write_json_to_file("trade.json", &trade_map) {
-        println!("Error in writing trade json: {}", e);
+    let changes = Changes { accounts: Some(&card_map), trades: Some(&trade_map), holdings: Some(&stock_map), ledger: Some(&ledger), responses: responses.as_ref(), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing stock purchase: {}", e);
         return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
     }
 
-    (StatusCode::OK, Json(json!({
-        "symbol": stock.symbol,
-        "hand": stock.hand,
-        "leverage": stock.leverage,
-        "cost": total_cost
-    }))).into_response()
+    response
 }
 
-pub async fn sell_stock(Json(stock): Json<SellStock>) -> impl IntoResponse {
-    let mut card_map: HashMap<u64, CardInfo> = match get_map("account.json") {
-        Ok(map) => map,
-        Err(e) => {
-            eprintln!("Error： {}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-        }
-    };
-
-    let data = match get_verified_card(&mut card_map, &stock.card_holder, &stock.target, &stock.token) {
-        Ok(d) => d,
-        Err(e) => return e.into_response(),
-    };
+pub async fn sell_stock(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(idempotency): State<SharedIdempotency>, State(quotes): State<SharedQuotes>, Verified(stock): Verified<SellStock>) -> impl IntoResponse {
+    let _account = locks.account(&stock.card_holder).await;
+    let replay_key = IdempotencyStore::key("sell_stock", &stock.card_holder, stock.idempotency_key.as_deref());
+    if let Some(response) = idempotency.replay(storage.as_ref(), replay_key.as_deref()) {
+        return response;
+    }
 
-    let price = match get_stock_price(stock.symbol.as_str()).await {
-        Ok(p) => p,
+    let quote = match get_stock_price(&quotes, stock.symbol.as_str()).await {
+        Ok(q) => q,
         Err(e) => {
             println!("Failed to get price: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get price!").into_response();
+            return (price_error_status(&e), "Failed to get price!").into_response();
         }
     };
 
-    let mut stock_map: HashMap<String, Vec<StockHold>> = match get_map("stockhold.json") {
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&stock.card_holder]) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     };
 
-    let mut trade_map: HashMap<i64, TradeHistory> = match get_map("trade.json") {
+    let data = match card_map.values_mut().find(|data| data.card_holder == stock.card_holder) {
+        Some(card) => card,
+        None => return (StatusCode::BAD_REQUEST, "No card holder found").into_response(),
+    };
+
+    let mut stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings_of(&stock.card_holder) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -152,82 +166,67 @@ pub async fn sell_stock(Json(stock): Json<SellStock>) -> impl IntoResponse {
         }
     };
 
-    let last_trade: i64;
-
-    if trade_map.is_empty() {
-        last_trade = 0;
-    } else {
-        let Some(last) = trade_map.keys().max() else { todo!() };
-        last_trade = *last;
-    };
+    let mut trade_map: HashMap<i64, TradeHistory> = HashMap::new();
+    let mut closed_map: HashMap<String, Vec<ClosedPosition>> = HashMap::new();
+    let mut ledger: HashMap<i64, LedgerEntry> = HashMap::new();
 
     let buy_vec = match stock_map.get_mut(&stock.card_holder) {
         Some(vec) => vec,
         None => return (StatusCode::BAD_REQUEST, "No stocks bought yet").into_response(),
     };
 
-    let pos = buy_vec.iter().position(|s| s.timestamp == stock.timestamp && s.stock.symbol == stock.symbol);
-
-    let buy_data = match pos {
-        Some(i) => buy_vec.remove(i),
-        None => return (StatusCode::BAD_REQUEST, "No stock holdings found").into_response(),
+    let lots = match pick_lots(buy_vec, &stock.symbol, stock.timestamp, stock.hand) {
+        Ok(lots) => lots,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
     };
 
-    let hand = buy_data.stock.hand;
-    let leverage = buy_data.stock.leverage;
-    let buy_price = buy_data.stock.price;
-    let buy_type = buy_data.stock.buy_type.as_str();
-    let sell_price = price;
-    let earning: Decimal;
-
-    if buy_type == "Long" {
-        earning = (sell_price - buy_price) * hand * leverage;
-    } else if buy_type == "Short" {
-        earning = (buy_price - sell_price) * hand * leverage;
-    } else {
-        return (StatusCode::BAD_REQUEST, "Wrong buy type").into_response();
-    }
-
-    let principal = buy_price * Decimal::from(hand) / Decimal::new(leverage.to_i64().unwrap(), 2);
-    let total_money = principal + earning;
-
+    let sell_price = quote.price;
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    data.balance += total_money;
-    let transaction_map = data.transaction.get_or_insert_with(HashMap::new);
-    transaction_map.insert(now, last_trade + 1);
-
-    if let Err(e) = write_json_to_file("stockhold.json", &stock_map) {
-        println!("Error in writing trade json: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
-    }
-
-    if let Err(e) = write_json_to_file("account.json", &card_map) {
-        println!("Error in writing card json: {}", e);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    let mut sold: Vec<ClosedPosition> = Vec::new();
+    for (timestamp, hand) in lots {
+        let pos = buy_vec.iter().position(|s| s.timestamp == timestamp && s.stock.symbol == stock.symbol).unwrap();
+        match settle_partial(data, &mut trade_map, &mut ledger, storage.as_ref(), buy_vec, pos, hand, sell_price, now, None) {
+            Ok(closed) => sold.push(closed),
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        }
     }
-
-    let trade_info = TradeHistory {
-        timestamp: now,
-        transaction_type: TransactionType::Credit { amount: total_money.to_f64().unwrap() },
-        target_user: String::from("Stock! Bot"),
+    let hand: Decimal = sold.iter().map(|lot| lot.hand).sum();
+    let earning: Decimal = sold.iter().map(|lot| lot.earning).sum();
+    // a single position keeps the old response shape
+    let leverage = if sold.len() == 1 { Some(sold[0].leverage) } else { None };
+    let lots: Vec<_> = sold.iter().map(|lot| json!({
+        "timestamp": lot.opened_at,
+        "hand": lot.hand,
+        "leverage": lot.leverage,
+        "earning": lot.earning
+    })).collect();
+    closed_map.entry(stock.card_holder.clone()).or_default().extend(sold);
+
+    let response = (StatusCode::OK, Json(json!({
+        "symbol": stock.symbol,
+        "hand": hand,
+        "leverage": leverage,
+        "earning": earning,
+        "price": sell_price,
+        "quoted_at": quote.quoted_at,
+        "lots": lots
+    }))).into_response();
+    let (responses, response) = match idempotency.capture(replay_key, response).await {
+        Ok(captured) => captured,
+        Err(response) => return response,
     };
 
-    trade_map.insert(last_trade + 1, trade_info);
-    if let Err(e) = write_json_to_file("trade.json", &trade_map) {
-        println!("Error in writing trade json: {}", e);
+    let changes = Changes { accounts: Some(&card_map), trades: Some(&trade_map), holdings: Some(&stock_map), closed: Some(&closed_map), ledger: Some(&ledger), responses: responses.as_ref(), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing stock sale: {}", e);
         return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
     }
 
-    (StatusCode::OK, Json(json!({
-        "symbol": stock.symbol,
-        "hand": hand,
-        "leverage": leverage,
-        "earning": earning
-    }))).into_response()
+    response
 }
 
-pub async fn check_stock_hold(Json(id): Json<Identification>) -> impl IntoResponse {
-    let stock_map: HashMap<String, Vec<StockHold>> = match get_map("stockhold.json") {
+pub async fn check_stock_hold(State(storage): State<SharedStorage>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings_of(&id.card_holder) {
         Ok(map) => map,
         Err(e) => {
             eprintln!("Error： {}", e);
@@ -243,100 +242,284 @@ pub async fn check_stock_hold(Json(id): Json<Identification>) -> impl IntoRespon
     (StatusCode::OK, Json(json!(result))).into_response()
 }
 
-pub async fn get_last_price(Json(name): Json<Symbol>) -> impl IntoResponse {
-    let symbol = match search_stock_name(name.symbol.as_str()).await {
+pub async fn get_last_price(State(quotes): State<SharedQuotes>, Json(name): Json<Symbol>) -> impl IntoResponse {
+    let symbol = match search_stock_name(&quotes, name.symbol.as_str()).await {
         Ok(s) => s,
-        Err(_) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "No stock symbol or name found").into_response();
+        Err(PriceError::NotFound(_)) => {
+            return (StatusCode::NOT_FOUND, "No stock symbol or name found").into_response();
+        }
+        Err(e) => {
+            println!("Failed to search symbol: {}", e);
+            return (StatusCode::SERVICE_UNAVAILABLE, "Failed to obtain price!").into_response();
         }
     };
 
-    let price = match get_stock_price(symbol.as_str()).await {
-        Ok(price) => price,
-        Err(_) => return String::from("Failed to obtain price!").into_response(),
+    let quote = match quotes.quote_symbol(&symbol).await {
+        Ok(quote) => quote,
+        Err(e) => {
+            println!("Failed to get price: {}", e);
+            return (price_error_status(&e), "Failed to obtain price!").into_response();
+        }
     };
 
-    (StatusCode::OK, Json(json!({ "symbol": symbol, "price": price.round_dp(2) }))).into_response()
+    (StatusCode::OK, Json(json!({
+        "symbol": symbol,
+        "price": quote.price.round_dp(2),
+        "quoted_at": quote.quoted_at
+    }))).into_response()
 }
 
 
 //stock functions
-pub async fn search_stock_name(name: &str) -> Result<String, Error> {
-    let name = name.to_string();
-    task::spawn_blocking(move || {
-        let provider = yahoo::YahooConnector::new().unwrap();
-        let resp = provider.search_ticker(&name).unwrap();
-        match resp.quotes.get(0) {
-            Some(quote) => Ok(quote.symbol.clone()),
-            None => Err(Error::new(ErrorKind::NotFound, "No stock symbol found")),
-        }
-    })
-        .await?
+// principal needed to open a position, leverage is given in percent
+pub fn position_cost(price: Decimal, hand: Decimal, leverage: Leverage) -> Decimal {
+    price * hand / Decimal::new(leverage.percent(), 2)
 }
 
-pub async fn get_stock_price(name: &str) -> Result<Decimal, Box<dyn StdError + Send + Sync>> {
-    let symbol = match search_stock_name(name).await {
-        Ok(s) => s,
-        Err(_) => {
-           return Err(Box::new(Error::new(ErrorKind::Other, "No stock symbol or name found")));
-        }
+// (earning, amount paid back) when `hold` is closed at `sell_price`
+pub fn close_position(hold: &StockHold, sell_price: Decimal) -> Result<(Decimal, Decimal), String> {
+    let stock = &hold.stock;
+    // leverage is in percent like in `position_cost`, 100 follows the price one to one
+    let exposure = stock.hand * stock.leverage / Decimal::ONE_HUNDRED;
+    let earning = match stock.buy_type {
+        Side::Long => (sell_price - stock.price) * exposure,
+        Side::Short => (stock.price - sell_price) * exposure,
     };
-    task::spawn_blocking(move || {
-        let provider = yahoo::YahooConnector::new()?;
-        let response = provider.get_latest_quotes(&symbol, "1d")?;
-        let quote = response.last_quote()?;
-        let price_f64 = quote.close;
-        let price = Decimal::from_f64(price_f64)
-            .ok_or_else(|| Box::new(Error::new(ErrorKind::Other, format!("Failed to transform {} to Decimal", price_f64))))?;
-        Ok(price.round_dp(2))
+    // holds stored before leverage was validated may not pass
+    let principal = position_cost(stock.price, stock.hand, Leverage::try_from(stock.leverage)?);
+    Ok((earning, principal + earning))
+}
+
+// removes holds[pos], pays the card back at `sell_price` and records the credit,
+// shared by /sell_stock, limit sell orders and stop-loss / take-profit triggers
+#[allow(clippy::too_many_arguments)]
+pub fn settle_sale(
+    data: &mut CardInfo,
+    trade_map: &mut HashMap<i64, TradeHistory>,
+    ledger: &mut HashMap<i64, LedgerEntry>,
+    ids: &dyn IdSource,
+    holds: &mut Vec<StockHold>,
+    pos: usize,
+    sell_price: Decimal,
+    now: i64,
+    reason: Option<&str>,
+) -> Result<ClosedPosition, String> {
+    let (earning, total_money) = close_position(&holds[pos], sell_price)?;
+    let hold = holds.remove(pos);
+
+    // a loss beyond the principal comes out of the balance, but never below zero
+    let paid = total_money.max(-data.balance.max(Decimal::ZERO));
+    let trade_id = record_stock_trade(data, trade_map, ledger, ids, now, paid, &format!("sell {}", hold.stock.symbol), reason)?;
+    Ok(ClosedPosition {
+        symbol: hold.stock.symbol,
+        buy_type: hold.stock.buy_type,
+        hand: hold.stock.hand,
+        leverage: hold.stock.leverage,
+        entry_price: hold.stock.price,
+        exit_price: sell_price,
+        opened_at: hold.timestamp,
+        closed_at: now,
+        earning: earning + paid - total_money,
+        reason: reason.map(String::from),
+        trade_id,
     })
-        .await?
 }
 
-pub async fn fetch_stock_history(name: &str, period: String, interval: String) -> Result<Vec<Quote>, Box<dyn StdError + Send + Sync>> {
-    let symbol = match search_stock_name(name).await {
-        Ok(s) => s,
-        Err(_) => {
-            return Err(Box::new(Error::new(ErrorKind::Other, "No stock symbol or name found")));
+// sells one held stock under the caller's locks if `check` still gives a reason for it,
+// used by the background tasks which decide on a snapshot taken before locking
+pub fn close_hold_if<F>(storage: &SharedStorage, card_holder: &str, timestamp: i64, symbol: &str, price: Decimal, check: F) -> Result<bool, String>
+where
+    F: FnOnce(&StockHold) -> Option<&'static str>,
+{
+    let mut stock_map = storage.load_holdings_of(card_holder)?;
+    let Some(holds) = stock_map.get_mut(card_holder) else { return Ok(false) };
+    let Some(pos) = holds.iter().position(|s| s.timestamp == timestamp && s.stock.symbol == symbol) else {
+        return Ok(false);
+    };
+    // sold or changed while we were waiting for the locks
+    let Some(reason) = check(&holds[pos]) else { return Ok(false) };
+
+    let mut card_map = storage.load_accounts_of(&[card_holder])?;
+    let mut trade_map = HashMap::new();
+    let mut closed_map: HashMap<String, Vec<ClosedPosition>> = HashMap::new();
+    let mut ledger = HashMap::new();
+    let Some(data) = card_map.values_mut().find(|data| data.card_holder == card_holder) else {
+        return Err(format!("No card holder found for {}", card_holder));
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let closed = settle_sale(data, &mut trade_map, &mut ledger, storage.as_ref(), holds, pos, price, now, Some(reason))?;
+    println!("Closed {} {} of {} at {} ({}), earning {}", symbol, timestamp, card_holder, price, reason, closed.earning);
+    closed_map.entry(card_holder.to_string()).or_default().push(closed);
+
+    storage.save_changes(Changes {
+        accounts: Some(&card_map),
+        trades: Some(&trade_map),
+        holdings: Some(&stock_map),
+        closed: Some(&closed_map),
+        ledger: Some(&ledger),
+        ..Changes::default()
+    })?;
+    Ok(true)
+}
+
+// sells `hand` hands of holds[pos]; a smaller amount is split off first and the rest
+// stays held with its original price, timestamp and triggers
+#[allow(clippy::too_many_arguments)]
+pub fn settle_partial(
+    data: &mut CardInfo,
+    trade_map: &mut HashMap<i64, TradeHistory>,
+    ledger: &mut HashMap<i64, LedgerEntry>,
+    ids: &dyn IdSource,
+    holds: &mut Vec<StockHold>,
+    pos: usize,
+    hand: Decimal,
+    sell_price: Decimal,
+    now: i64,
+    reason: Option<&str>,
+) -> Result<ClosedPosition, String> {
+    let held = holds[pos].stock.hand;
+    if hand <= Decimal::ZERO || hand > held {
+        return Err(format!("Can only sell between 0 and {} hands of this stock", held));
+    }
+    if hand < held {
+        let mut part = holds[pos].clone();
+        part.stock.hand = hand;
+        holds[pos].stock.hand -= hand;
+        holds.insert(pos, part);
+    }
+    settle_sale(data, trade_map, ledger, ids, holds, pos, sell_price, now, reason)
+}
+
+// (timestamp, hands) to sell: one position when `timestamp` is given, otherwise
+// `hand` hands taken from the oldest positions of `symbol` first
+pub fn pick_lots(holds: &[StockHold], symbol: &str, timestamp: Option<i64>, hand: Option<Decimal>) -> Result<Vec<(i64, Decimal)>, String> {
+    if let Some(timestamp) = timestamp {
+        let hold = holds.iter().find(|s| s.timestamp == timestamp && s.stock.symbol == symbol)
+            .ok_or_else(|| String::from("No stock holdings found"))?;
+        return Ok(vec![(timestamp, hand.unwrap_or(hold.stock.hand))]);
+    }
+
+    let Some(mut remaining) = hand else {
+        return Err(String::from("Give either the timestamp of a held stock or how many hands to sell"));
+    };
+    if remaining <= Decimal::ZERO {
+        return Err(String::from("Hand must be positive"));
+    }
+
+    let mut positions: Vec<&StockHold> = holds.iter().filter(|s| s.stock.symbol == symbol).collect();
+    positions.sort_by_key(|s| s.timestamp);
+    let available: Decimal = positions.iter().map(|s| s.stock.hand).sum();
+    if available < remaining {
+        return Err(format!("Only {} hands of {} held", available, symbol));
+    }
+
+    let mut lots = Vec::new();
+    for hold in positions {
+        if remaining <= Decimal::ZERO {
+            break;
         }
+        let take = remaining.min(hold.stock.hand);
+        lots.push((hold.timestamp, take));
+        remaining -= take;
+    }
+    Ok(lots)
+}
+
+// both prices are optional, each has to sit on its side of the current `price` so that
+// neither fires on the watcher's next tick: below it for a Long stop-loss, above it for a Long take-profit
+pub fn check_triggers(buy_type: Side, price: Decimal, stop_loss: Option<Decimal>, take_profit: Option<Decimal>) -> Result<(), String> {
+    if stop_loss.iter().chain(take_profit.iter()).any(|p| *p <= Decimal::ZERO) {
+        return Err(String::from("Stop-loss and take-profit must be positive"));
+    }
+    let (stop_ok, take_ok, stop_side, take_side) = match buy_type {
+        Side::Long => (stop_loss.is_none_or(|stop| stop < price), take_profit.is_none_or(|take| take > price), "below", "above"),
+        Side::Short => (stop_loss.is_none_or(|stop| stop > price), take_profit.is_none_or(|take| take < price), "above", "below"),
     };
-    let period = period.to_string();
-    let interval = interval.to_string();
-
-    task::spawn_blocking(move || {
-        let provider = yahoo::YahooConnector::new()?;
-        let response = provider.get_quote_range(&symbol, &interval, &period)?;
-        let quotes = response.quotes()?;
-        Ok(quotes)
-    }).await?
+    if !stop_ok {
+        return Err(format!("Stop-loss of a {} position must be {} the current price of {}", buy_type, stop_side, price));
+    }
+    if !take_ok {
+        return Err(format!("Take-profit of a {} position must be {} the current price of {}", buy_type, take_side, price));
+    }
+    Ok(())
 }
 
-pub async fn get_stock_history(Json(history): Json<StockHistory>) -> impl IntoResponse {
-    let quotes = match fetch_stock_history(history.symbol.as_str(), history.period, history.interval).await {
-        Ok(quotes) => quotes,
-        Err(_) => return String::from("Failed to obtain stock history!").into_response(),
+// adds a "Stock! Bot" trade under a new trade id from `ids`, links it to the card and posts `amount`
+// between the card and stock clearing; a positive amount pays the card
+#[allow(clippy::too_many_arguments)]
+pub fn record_stock_trade(
+    data: &mut CardInfo,
+    trade_map: &mut HashMap<i64, TradeHistory>,
+    ledger: &mut HashMap<i64, LedgerEntry>,
+    ids: &dyn IdSource,
+    now: i64,
+    amount: Decimal,
+    memo: &str,
+    reason: Option<&str>,
+) -> Result<i64, String> {
+    let transaction_type = if amount < Decimal::ZERO {
+        TransactionType::Debit { amount: (-amount).to_f64().unwrap() }
+    } else {
+        TransactionType::Credit { amount: amount.to_f64().unwrap() }
     };
-    (StatusCode::OK, Json(json!(quotes))).into_response()
+    let trade_id = ids.next_id(Sequence::Trade)?;
+    data.transaction.get_or_insert_with(TradeIndex::default).push(now, trade_id);
+    trade_map.insert(trade_id, TradeHistory {
+        timestamp: now,
+        transaction_type,
+        target_user: String::from("Stock! Bot"),
+        linked_trade: None,
+        reason: reason.map(String::from),
+    });
+    post_card(ledger, ids, data, STOCK_CLEARING, amount, now, Some(trade_id), memo)?;
+    Ok(trade_id)
+}
+
+pub async fn search_stock_name(quotes: &SharedQuotes, name: &str) -> Result<String, PriceError> {
+    quotes.symbol(name).await
 }
 
-pub fn get_verified_card<'a>(
-    card_map: &'a mut HashMap<u64, CardInfo>,
-    card_holder: &str,
-    target: &str,
-    token: &str,
-) -> Result<&'a mut CardInfo, (StatusCode, &'static str)> {
-    let data = card_map.values_mut().find(|data| data.card_holder == card_holder)
-        .ok_or((StatusCode::BAD_REQUEST, "No card holder found"))?;
+pub async fn get_stock_price(quotes: &SharedQuotes, name: &str) -> Result<PriceQuote, PriceError> {
+    quotes.quote(name).await
+}
 
-    let connection_map = data.connection.as_ref()
-        .ok_or((StatusCode::BAD_REQUEST, "This card is not connected to any platform"))?;
+// one quote per symbol for a background pass, symbols without a price are left out
+pub async fn quote_symbols(quotes: &SharedQuotes, symbols: HashSet<String>) -> HashMap<String, Decimal> {
+    let mut prices = HashMap::new();
+    for symbol in symbols {
+        match get_stock_price(quotes, symbol.as_str()).await {
+            Ok(quote) => {
+                prices.insert(symbol, quote.price);
+            }
+            Err(e) => println!("Failed to get price of {}: {}", symbol, e),
+        }
+    }
+    prices
+}
 
-    let stored_token_vec = connection_map.get(target)
-        .ok_or((StatusCode::BAD_REQUEST, "No platform connection record found"))?;
+pub async fn fetch_stock_history(quotes: &SharedQuotes, name: &str, period: String, interval: String) -> Result<Vec<Quote>, PriceError> {
+    let symbol = search_stock_name(quotes, name).await?;
+    let provider = quotes.provider().clone();
+    task::spawn_blocking(move || provider.history(&symbol, &period, &interval))
+        .await
+        .map_err(|e| PriceError::Unavailable(e.to_string()))?
+}
+
+pub async fn get_stock_history(State(quotes): State<SharedQuotes>, Json(history): Json<StockHistory>) -> impl IntoResponse {
+    let quotes = match fetch_stock_history(&quotes, history.symbol.as_str(), history.period, history.interval).await {
+        Ok(quotes) => quotes,
+        Err(e) => {
+            println!("Failed to get history: {}", e);
+            return (price_error_status(&e), "Failed to obtain stock history!").into_response();
+        }
+    };
+    (StatusCode::OK, Json(json!(quotes))).into_response()
+}
 
-    let matched = stored_token_vec.iter().any(|t| t.target == target && t.token == token);
-    if !matched {
-        return Err((StatusCode::BAD_REQUEST, "Failed to verify"));
+fn price_error_status(e: &PriceError) -> StatusCode {
+    match e {
+        PriceError::NotFound(_) => StatusCode::NOT_FOUND,
+        PriceError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
     }
-    Ok(data)
 }
@@ -0,0 +1,34 @@
+use rust_decimal::Decimal;
+use structure::{FieldError, Hand, Leverage};
+
+pub struct CardLimits {
+    // in percent like Leverage, never above MAX_LEVERAGE
+    pub max_leverage: i64,
+    // hands in a single purchase
+    pub max_hand: Decimal,
+}
+
+// cards with a type we don't know get the Classic limits
+pub fn card_limits(card_type: &str) -> CardLimits {
+    let (max_leverage, max_hand) = match card_type {
+        "Infinite" => (1000, 10000),
+        "Platinum" => (500, 1000),
+        _ => (200, 100),
+    };
+    CardLimits {
+        max_leverage,
+        max_hand: Decimal::from(max_hand),
+    }
+}
+
+// checked when a position is bought or a buy order placed, refused purchases are answered with a 422
+pub fn check_purchase(card_type: &str, hand: Hand, leverage: Leverage) -> Result<(), FieldError> {
+    let limits = card_limits(card_type);
+    if leverage.percent() > limits.max_leverage {
+        return Err(FieldError::new("leverage", format!("{} cards allow a leverage of at most {}", card_type, limits.max_leverage)));
+    }
+    if hand.get() > limits.max_hand {
+        return Err(FieldError::new("hand", format!("{} cards allow at most {} hands per purchase", card_type, limits.max_hand)));
+    }
+    Ok(())
+}
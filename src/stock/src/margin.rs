@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::{extract::{Json, State}, response::IntoResponse};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use serde::Serialize;
+use serde_json::json;
+use structure::{AccountAuth, Leverage, StockHold};
+use storage::{SharedLocks, SharedStorage};
+use auth::Verified;
+use crate::cache::env_secs;
+use crate::{close_hold_if, close_position, position_cost, quote_symbols, SharedQuotes};
+
+// leverage is in percent like in BuyStock, anything above this is refused
+pub const MAX_LEVERAGE: i64 = 1000;
+
+pub struct MarginTier {
+    pub max_leverage: i64,
+    // share of the principal the position has to keep, below it the position is liquidated
+    pub maintenance: Decimal,
+    // share of the principal below which a margin warning is issued
+    pub warning: Decimal,
+}
+
+pub fn margin_tier(leverage: Decimal) -> Option<MarginTier> {
+    let (max_leverage, maintenance, warning) = match leverage.to_i64()? {
+        1..=100 => (100, 25, 40),
+        101..=200 => (200, 30, 45),
+        201..=500 => (500, 40, 55),
+        501..=MAX_LEVERAGE => (MAX_LEVERAGE, 50, 65),
+        _ => return None,
+    };
+    Some(MarginTier {
+        max_leverage,
+        maintenance: Decimal::new(maintenance, 2),
+        warning: Decimal::new(warning, 2),
+    })
+}
+
+#[derive(Serialize, Clone)]
+pub struct MarginStatus {
+    pub symbol: String,
+    pub timestamp: i64,
+    pub price: Decimal,
+    // what selling now would pay back
+    pub equity: Decimal,
+    pub maintenance: Decimal,
+    // "ok", "warning", "liquidation" or "liquidated"
+    pub level: String,
+    pub checked_at: i64,
+}
+
+pub fn margin_status(hold: &StockHold, price: Decimal, now: i64) -> Result<MarginStatus, String> {
+    let (_, equity) = close_position(hold, price)?;
+    let principal = position_cost(hold.stock.price, hold.stock.hand, Leverage::try_from(hold.stock.leverage)?);
+    // positions opened before the limits existed are held to the strictest tier
+    let tier = margin_tier(hold.stock.leverage.min(Decimal::from(MAX_LEVERAGE)))
+        .ok_or_else(|| String::from("Leverage must be positive"))?;
+
+    let maintenance = principal * tier.maintenance;
+    let level = if equity < maintenance {
+        "liquidation"
+    } else if equity < principal * tier.warning {
+        "warning"
+    } else {
+        "ok"
+    };
+
+    Ok(MarginStatus {
+        symbol: hold.stock.symbol.clone(),
+        timestamp: hold.timestamp,
+        price,
+        equity,
+        maintenance,
+        level: level.to_string(),
+        checked_at: now,
+    })
+}
+
+pub type SharedMargin = Arc<MarginMonitor>;
+
+// result of the last mark-to-market per card holder
+#[derive(Default)]
+pub struct MarginMonitor {
+    last: Mutex<HashMap<String, Vec<MarginStatus>>>,
+}
+
+impl MarginMonitor {
+    pub fn positions(&self, card_holder: &str) -> Vec<MarginStatus> {
+        let last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        last.get(card_holder).cloned().unwrap_or_default()
+    }
+
+    fn publish(&self, snapshot: HashMap<String, Vec<MarginStatus>>) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = snapshot;
+    }
+}
+
+pub async fn get_margin_status(State(margin): State<SharedMargin>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let positions = margin.positions(&id.card_holder);
+    let warnings = positions.iter().filter(|p| p.level != "ok").count();
+    (StatusCode::OK, Json(json!({
+        "positions": positions,
+        "warnings": warnings
+    }))).into_response()
+}
+
+// MARGIN_CHECK_INTERVAL in seconds, default 10
+pub fn margin_interval_from_env() -> Result<Duration, String> {
+    env_secs("MARGIN_CHECK_INTERVAL", 10)
+}
+
+// background task spawned from main, never returns
+pub async fn run_margin_monitor(storage: SharedStorage, locks: SharedLocks, quotes: SharedQuotes, margin: SharedMargin, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = mark_to_market(&storage, &locks, &quotes, &margin).await {
+            eprintln!("Margin check failed： {}", e);
+        }
+    }
+}
+
+// values every position at the current price, warns when equity runs low
+// and sells positions that fell below maintenance
+pub async fn mark_to_market(storage: &SharedStorage, locks: &SharedLocks, quotes: &SharedQuotes, margin: &SharedMargin) -> Result<usize, String> {
+    let holdings = storage.load_holdings()?;
+    let symbols = holdings.values().flatten().map(|hold| hold.stock.symbol.clone()).collect();
+    let prices = quote_symbols(quotes, symbols).await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+    let mut snapshot: HashMap<String, Vec<MarginStatus>> = HashMap::new();
+    let mut liquidated = 0;
+    for (card_holder, holds) in holdings {
+        for hold in holds {
+            let Some(&price) = prices.get(&hold.stock.symbol) else { continue };
+            let mut status = match margin_status(&hold, price, now) {
+                Ok(status) => status,
+                Err(e) => {
+                    println!("Skipping {} {} of {}: {}", hold.stock.symbol, hold.timestamp, card_holder, e);
+                    continue;
+                }
+            };
+
+            if status.level == "warning" {
+                println!("Margin warning for {}: {} {} equity {} maintenance {}", card_holder, status.symbol, status.timestamp, status.equity, status.maintenance);
+            } else if status.level == "liquidation" {
+                let _account = locks.account(&card_holder).await;
+                let _store = locks.store().await;
+                let below = |hold: &StockHold| {
+                    margin_status(hold, price, now).ok()
+                        .filter(|s| s.level == "liquidation")
+                        .map(|_| "liquidation")
+                };
+                if close_hold_if(storage, &card_holder, hold.timestamp, &hold.stock.symbol, price, below)? {
+                    status.level = String::from("liquidated");
+                    liquidated += 1;
+                }
+            }
+            snapshot.entry(card_holder.clone()).or_default().push(status);
+        }
+    }
+
+    margin.publish(snapshot);
+    Ok(liquidated)
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::{extract::{Json, State}, response::IntoResponse};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use serde_json::json;
+use structure::{AccountAuth, CancelOrder, CardInfo, ClosedPosition, LedgerEntry, LimitOrder, OrderSide, OrderStatus, PlaceOrder, Side, Stock, StockHold, TradeHistory};
+use function::{check_balance, Sequence};
+use storage::{Changes, IdempotencyStore, SharedIdempotency, SharedLocks, SharedStorage};
+use auth::Verified;
+use crate::cache::env_secs;
+use crate::{check_purchase, position_cost, price_error_status, quote_symbols, record_stock_trade, search_stock_name, settle_sale, SharedQuotes};
+
+pub async fn place_order(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(idempotency): State<SharedIdempotency>, State(quotes): State<SharedQuotes>, Verified(order): Verified<PlaceOrder>) -> impl IntoResponse {
+    let _account = locks.account(&order.card_holder).await;
+    let replay_key = IdempotencyStore::key("place_order", &order.card_holder, order.idempotency_key.as_deref());
+    if let Some(response) = idempotency.replay(storage.as_ref(), replay_key.as_deref()) {
+        return response;
+    }
+
+    if order.limit_price <= Decimal::ZERO {
+        return (StatusCode::BAD_REQUEST, "Limit price must be positive").into_response();
+    }
+
+    // fail fast on unknown symbols instead of leaving an order that can never fill
+    if let Err(e) = search_stock_name(&quotes, order.symbol.as_str()).await {
+        println!("Failed to search symbol: {}", e);
+        return (price_error_status(&e), "No stock symbol or name found").into_response();
+    }
+
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&order.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let mut trade_map: HashMap<i64, TradeHistory> = HashMap::new();
+    let mut ledger: HashMap<i64, LedgerEntry> = HashMap::new();
+
+    let order_map: HashMap<i64, LimitOrder> = match storage.load_orders_of(&order.card_holder) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let data = match card_map.values_mut().find(|data| data.card_holder == order.card_holder) {
+        Some(card) => card,
+        None => return (StatusCode::BAD_REQUEST, "No card holder found").into_response(),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+    let limit_order = if order.side == OrderSide::Buy {
+        let (Some(buy_type), Some(hand), Some(leverage)) = (order.buy_type, order.hand, order.leverage) else {
+            return (StatusCode::BAD_REQUEST, "Buy orders need buy_type, hand and leverage").into_response();
+        };
+        if let Err(e) = check_purchase(&data.card_type, hand, leverage) {
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json(e)).into_response();
+        }
+
+        // the most the position can cost, what a better fill saves is refunded
+        let reserved = position_cost(order.limit_price, hand.get(), leverage);
+        if !check_balance(&data.balance, reserved) {
+            return (StatusCode::BAD_REQUEST, "Insufficient balance").into_response();
+        }
+        if let Err(e) = record_stock_trade(data, &mut trade_map, &mut ledger, storage.as_ref(), now, -reserved, &format!("reserve buy order {}", order.symbol), None) {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+
+        LimitOrder {
+            card_holder: order.card_holder.clone(),
+            side: order.side,
+            buy_type,
+            symbol: order.symbol.clone(),
+            hand: hand.get(),
+            leverage: leverage.get(),
+            limit_price: order.limit_price,
+            reserved,
+            hold_timestamp: None,
+            created_at: now,
+            status: OrderStatus::Open,
+            filled_price: None,
+            closed_at: None,
+        }
+    } else {
+        let Some(timestamp) = order.timestamp else {
+            return (StatusCode::BAD_REQUEST, "Sell orders need the timestamp of the held stock").into_response();
+        };
+
+        let stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings_of(&order.card_holder) {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("Error： {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+            }
+        };
+
+        let hold = stock_map.get(&order.card_holder)
+            .and_then(|holds| holds.iter().find(|s| s.timestamp == timestamp && s.stock.symbol == order.symbol));
+        let Some(hold) = hold else {
+            return (StatusCode::BAD_REQUEST, "No stock holdings found").into_response();
+        };
+
+        let already_ordered = order_map.values().any(|o| {
+            o.status == OrderStatus::Open && o.hold_timestamp == Some(timestamp) && o.symbol == order.symbol
+        });
+        if already_ordered {
+            return (StatusCode::CONFLICT, "This stock already has an open sell order").into_response();
+        }
+
+        LimitOrder {
+            card_holder: order.card_holder.clone(),
+            side: order.side,
+            buy_type: hold.stock.buy_type,
+            symbol: order.symbol.clone(),
+            hand: hold.stock.hand,
+            leverage: hold.stock.leverage,
+            limit_price: order.limit_price,
+            reserved: Decimal::ZERO,
+            hold_timestamp: Some(timestamp),
+            created_at: now,
+            status: OrderStatus::Open,
+            filled_price: None,
+            closed_at: None,
+        }
+    };
+
+    let order_id = match storage.next_id(Sequence::Order) {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+    let response = (StatusCode::OK, Json(json!({
+        "order_id": order_id,
+        "order": limit_order
+    }))).into_response();
+    let (responses, response) = match idempotency.capture(replay_key, response).await {
+        Ok(captured) => captured,
+        Err(response) => return response,
+    };
+    let new_order = HashMap::from([(order_id, limit_order)]);
+
+    let changes = Changes { accounts: Some(&card_map), trades: Some(&trade_map), orders: Some(&new_order), ledger: Some(&ledger), responses: responses.as_ref(), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing order: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+
+    response
+}
+
+pub async fn list_orders(State(storage): State<SharedStorage>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let order_map: HashMap<i64, LimitOrder> = match storage.load_orders_of(&id.card_holder) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let orders: BTreeMap<i64, LimitOrder> = order_map.into_iter()
+        .filter(|(_, o)| o.status == OrderStatus::Open)
+        .collect();
+
+    (StatusCode::OK, Json(json!(orders))).into_response()
+}
+
+pub async fn cancel_order(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, Verified(cancel): Verified<CancelOrder>) -> impl IntoResponse {
+    let _account = locks.account(&cancel.card_holder).await;
+    let _store = locks.store().await;
+    let mut card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&cancel.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let mut trade_map: HashMap<i64, TradeHistory> = HashMap::new();
+    let mut ledger: HashMap<i64, LedgerEntry> = HashMap::new();
+
+    let mut order_map: HashMap<i64, LimitOrder> = match storage.load_orders_of(&cancel.card_holder) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let order = match order_map.get_mut(&cancel.order_id) {
+        Some(o) if o.card_holder == cancel.card_holder => o,
+        _ => return (StatusCode::NOT_FOUND, "No order found").into_response(),
+    };
+    if order.status != OrderStatus::Open {
+        return (StatusCode::CONFLICT, "Order is no longer open").into_response();
+    }
+
+    let data = match card_map.values_mut().find(|data| data.card_holder == cancel.card_holder) {
+        Some(card) => card,
+        None => return (StatusCode::BAD_REQUEST, "No card holder found").into_response(),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let refund = order.reserved;
+    if refund > Decimal::ZERO
+        && let Err(e) = record_stock_trade(data, &mut trade_map, &mut ledger, storage.as_ref(), now, refund, &format!("refund order {}", cancel.order_id), None)
+    {
+        eprintln!("Error： {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+    order.status = OrderStatus::Cancelled;
+    order.closed_at = Some(now);
+    let cancelled = HashMap::from([(cancel.order_id, order.clone())]);
+
+    let changes = Changes { accounts: Some(&card_map), trades: Some(&trade_map), orders: Some(&cancelled), ledger: Some(&ledger), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in cancelling order: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+
+    (StatusCode::OK, Json(json!({
+        "order_id": cancel.order_id,
+        "refund": refund
+    }))).into_response()
+}
+
+// ORDER_MATCH_INTERVAL in seconds, default 5
+pub fn match_interval_from_env() -> Result<Duration, String> {
+    env_secs("ORDER_MATCH_INTERVAL", 5)
+}
+
+// background task spawned from main, never returns
+pub async fn run_order_matching(storage: SharedStorage, locks: SharedLocks, quotes: SharedQuotes, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = match_orders(&storage, &locks, &quotes).await {
+            eprintln!("Order matching failed： {}", e);
+        }
+    }
+}
+
+// buying Long or closing a Short wants a low price, the other two a high one
+fn wants_low(order: &LimitOrder) -> bool {
+    (order.side == OrderSide::Buy) == (order.buy_type == Side::Long)
+}
+
+fn crossed(order: &LimitOrder, price: Decimal) -> bool {
+    if wants_low(order) {
+        price <= order.limit_price
+    } else {
+        price >= order.limit_price
+    }
+}
+
+// an order fills at the market price when that is better for the card than its limit
+fn fill_price(order: &LimitOrder, price: Decimal) -> Decimal {
+    if wants_low(order) {
+        price.min(order.limit_price)
+    } else {
+        price.max(order.limit_price)
+    }
+}
+
+// one pass over the open orders, each fill runs under the same locks as a request from that card
+pub async fn match_orders(storage: &SharedStorage, locks: &SharedLocks, quotes: &SharedQuotes) -> Result<usize, String> {
+    let open: Vec<(i64, LimitOrder)> = storage.load_open_orders()?.into_iter().collect();
+    if open.is_empty() {
+        return Ok(0);
+    }
+    let stock_map = storage.load_holdings()?;
+
+    let prices = quote_symbols(quotes, open.iter().map(|(_, o)| o.symbol.clone()).collect()).await;
+    let mut filled = 0;
+    for (order_id, order) in open {
+        // sell orders whose stock was sold some other way are cancelled without a price
+        let held = order.side == OrderSide::Buy || stock_map.get(&order.card_holder).is_some_and(|holds| {
+            holds.iter().any(|s| Some(s.timestamp) == order.hold_timestamp && s.stock.symbol == order.symbol)
+        });
+        if !held {
+            let _account = locks.account(&order.card_holder).await;
+            let _store = locks.store().await;
+            fill_order(storage, order_id, &order.card_holder, None)?;
+            continue;
+        }
+
+        let Some(&price) = prices.get(&order.symbol) else { continue };
+        if !crossed(&order, price) {
+            continue;
+        }
+
+        let _account = locks.account(&order.card_holder).await;
+        let _store = locks.store().await;
+        if fill_order(storage, order_id, &order.card_holder, Some(price))? {
+            filled += 1;
+        }
+    }
+    Ok(filled)
+}
+
+// fills the order at `market` or its better limit, an order without a price is only cancelled if its stock is gone
+fn fill_order(storage: &SharedStorage, order_id: i64, card_holder: &str, market: Option<Decimal>) -> Result<bool, String> {
+    let mut order_map = storage.load_orders_of(card_holder)?;
+    let Some(order) = order_map.get_mut(&order_id) else { return Ok(false) };
+    // cancelled or filled while we were waiting for the locks
+    if order.status != OrderStatus::Open {
+        return Ok(false);
+    }
+
+    let mut card_map = storage.load_accounts_of(&[card_holder])?;
+    let mut trade_map = HashMap::new();
+    let mut stock_map = storage.load_holdings_of(card_holder)?;
+    let mut closed_map: HashMap<String, Vec<ClosedPosition>> = HashMap::new();
+    let mut ledger = HashMap::new();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+
+    let Some(data) = card_map.values_mut().find(|data| data.card_holder == order.card_holder) else {
+        return Err(format!("No card holder found for order {}", order_id));
+    };
+
+    let price = market.map(|market| fill_price(order, market));
+    if order.side == OrderSide::Buy {
+        let Some(price) = price else { return Ok(false) };
+        // the limit price was reserved when the order was placed, the principal is proportional
+        // to the price so a better fill hands back the same share of it
+        let cost = order.reserved * price / order.limit_price;
+        let refund = order.reserved - cost;
+        if refund > Decimal::ZERO {
+            record_stock_trade(data, &mut trade_map, &mut ledger, storage.as_ref(), now, refund, &format!("refund order {}", order_id), None)?;
+        }
+        stock_map.entry(order.card_holder.clone()).or_default().push(StockHold {
+            timestamp: now,
+            stock: Stock {
+                buy_type: order.buy_type,
+                symbol: order.symbol.clone(),
+                hand: order.hand,
+                leverage: order.leverage,
+                price,
+            },
+            stop_loss: None,
+            take_profit: None,
+        });
+        order.status = OrderStatus::Filled;
+        order.filled_price = Some(price);
+    } else {
+        let holds = stock_map.entry(order.card_holder.clone()).or_default();
+        let pos = holds.iter().position(|s| Some(s.timestamp) == order.hold_timestamp && s.stock.symbol == order.symbol);
+        if pos.is_some() && price.is_none() {
+            return Ok(false);
+        }
+        let sold = pos.zip(price).map(|(i, price)| settle_sale(data, &mut trade_map, &mut ledger, storage.as_ref(), holds, i, price, now, Some("limit_order")));
+        match sold {
+            Some(Ok(closed)) => {
+                order.filled_price = Some(closed.exit_price);
+                closed_map.entry(order.card_holder.clone()).or_default().push(closed);
+                order.status = OrderStatus::Filled;
+            }
+            // the hold can't be sold as it is, e.g. a leverage stored before it was validated;
+            // reconciliation reports it as a dangling hold. Only the order is written, the hold stays
+            Some(Err(e)) => {
+                eprintln!("Cancelling sell order {} of {}, its stock can't be sold： {}", order_id, order.card_holder, e);
+                order.status = OrderStatus::Cancelled;
+                order.closed_at = Some(now);
+                let order_map = HashMap::from([(order_id, order.clone())]);
+                storage.save_changes(Changes { orders: Some(&order_map), ..Changes::default() })?;
+                return Ok(false);
+            }
+            // the position was already sold by hand or by a trigger
+            None => order.status = OrderStatus::Cancelled,
+        }
+    }
+    order.closed_at = Some(now);
+    let filled = order.status == OrderStatus::Filled;
+    let order_map = HashMap::from([(order_id, order.clone())]);
+
+    storage.save_changes(Changes {
+        accounts: Some(&card_map),
+        trades: Some(&trade_map),
+        holdings: Some(&stock_map),
+        orders: Some(&order_map),
+        closed: Some(&closed_map),
+        ledger: Some(&ledger),
+        ..Changes::default()
+    })?;
+    Ok(filled)
+}
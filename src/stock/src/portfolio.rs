@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use axum::{extract::{Json, State}, response::IntoResponse};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::json;
+use structure::{AccountAuth, CardInfo, Leverage, OrderStatus, Side, StockHold};
+use storage::SharedStorage;
+use auth::Verified;
+use crate::{close_position, get_stock_price, position_cost, price_error_status, SharedQuotes};
+
+#[derive(Serialize, Clone)]
+pub struct PositionValue {
+    pub symbol: String,
+    pub timestamp: i64,
+    pub buy_type: Side,
+    pub hand: Decimal,
+    pub leverage: Decimal,
+    pub buy_price: Decimal,
+    pub price: Decimal,
+    // principal paid when the position was opened
+    pub cost: Decimal,
+    // what /sell_stock would pay back at `price`
+    pub value: Decimal,
+    pub unrealized_pnl: Decimal,
+}
+
+// marks one position to `price` with the same math as /sell_stock
+pub fn value_position(hold: &StockHold, price: Decimal) -> Result<PositionValue, String> {
+    let (earning, value) = close_position(hold, price)?;
+    Ok(PositionValue {
+        symbol: hold.stock.symbol.clone(),
+        timestamp: hold.timestamp,
+        buy_type: hold.stock.buy_type,
+        hand: hold.stock.hand,
+        leverage: hold.stock.leverage,
+        buy_price: hold.stock.price,
+        price,
+        cost: position_cost(hold.stock.price, hold.stock.hand, Leverage::try_from(hold.stock.leverage)?),
+        value,
+        unrealized_pnl: earning,
+    })
+}
+
+pub async fn get_portfolio(State(storage): State<SharedStorage>, State(quotes): State<SharedQuotes>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let card_map: HashMap<u64, CardInfo> = match storage.load_accounts_of(&[&id.card_holder]) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let data = match card_map.values().find(|data| data.card_holder == id.card_holder) {
+        Some(card) => card,
+        None => return (StatusCode::BAD_REQUEST, "No card holder found").into_response(),
+    };
+
+    let stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings_of(&id.card_holder) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+    let holds = stock_map.get(&id.card_holder).cloned().unwrap_or_default();
+
+    // funds of open buy orders left the balance when the order was placed but still belong to the card
+    let reserved: Decimal = match storage.load_orders_of(&id.card_holder) {
+        Ok(map) => map.values().filter(|o| o.status == OrderStatus::Open).map(|o| o.reserved).sum(),
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let mut prices: HashMap<String, Decimal> = HashMap::new();
+    let mut positions = Vec::new();
+    for hold in &holds {
+        let price = match prices.get(&hold.stock.symbol) {
+            Some(price) => *price,
+            None => match get_stock_price(&quotes, hold.stock.symbol.as_str()).await {
+                Ok(quote) => {
+                    prices.insert(hold.stock.symbol.clone(), quote.price);
+                    quote.price
+                }
+                Err(e) => {
+                    println!("Failed to get price: {}", e);
+                    return (price_error_status(&e), "Failed to get price!").into_response();
+                }
+            },
+        };
+
+        match value_position(hold, price) {
+            Ok(position) => positions.push(position),
+            Err(e) => {
+                eprintln!("Error valuing {} {} of {}： {}", hold.stock.symbol, hold.timestamp, id.card_holder, e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+            }
+        }
+    }
+    positions.sort_by_key(|p| p.timestamp);
+
+    let positions_value: Decimal = positions.iter().map(|p| p.value).sum();
+    let unrealized_pnl: Decimal = positions.iter().map(|p| p.unrealized_pnl).sum();
+
+    (StatusCode::OK, Json(json!({
+        "balance": data.balance,
+        "reserved": reserved,
+        "positions": positions,
+        "positions_value": positions_value,
+        "unrealized_pnl": unrealized_pnl,
+        "equity": data.balance + reserved + positions_value
+    }))).into_response()
+}
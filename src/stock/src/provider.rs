@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use rust_decimal::{Decimal, prelude::FromPrimitive};
+use serde::{Deserialize, Serialize};
+use yahoo_finance_api as yahoo;
+use yahoo_finance_api::Quote;
+use function::get_map;
+
+pub type SharedPrices = Arc<dyn PriceProvider>;
+
+#[derive(Debug)]
+pub enum PriceError {
+    NotFound(String),
+    Unavailable(String),
+}
+
+impl fmt::Display for PriceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PriceError::NotFound(name) => write!(f, "No stock symbol or name found for {}", name),
+            PriceError::Unavailable(reason) => write!(f, "Market data unavailable: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PriceError {}
+
+// blocking calls, the async wrappers in lib.rs run them on the blocking pool
+pub trait PriceProvider: Send + Sync {
+    fn search_symbol(&self, name: &str) -> Result<String, PriceError>;
+    fn latest_price(&self, symbol: &str) -> Result<Decimal, PriceError>;
+    fn history(&self, symbol: &str, period: &str, interval: &str) -> Result<Vec<Quote>, PriceError>;
+}
+
+// PRICE_PROVIDER=yahoo (default) or fixture, PRICE_FIXTURE points at the fixture json
+pub fn provider_from_env() -> Result<SharedPrices, String> {
+    let provider = env::var("PRICE_PROVIDER").unwrap_or_else(|_| String::from("yahoo"));
+    match provider.to_lowercase().as_str() {
+        "yahoo" => Ok(Arc::new(YahooProvider)),
+        "fixture" => {
+            let path = env::var("PRICE_FIXTURE").unwrap_or_else(|_| String::from("prices.json"));
+            Ok(Arc::new(ScriptedProvider::from_fixture(&path)?))
+        }
+        other => Err(format!("Unknown price provider：{}", other)),
+    }
+}
+
+pub struct YahooProvider;
+
+fn unavailable<E: fmt::Display>(e: E) -> PriceError {
+    PriceError::Unavailable(e.to_string())
+}
+
+impl PriceProvider for YahooProvider {
+    fn search_symbol(&self, name: &str) -> Result<String, PriceError> {
+        let provider = yahoo::YahooConnector::new().map_err(unavailable)?;
+        let resp = provider.search_ticker(name).map_err(unavailable)?;
+        match resp.quotes.first() {
+            Some(quote) => Ok(quote.symbol.clone()),
+            None => Err(PriceError::NotFound(name.to_string())),
+        }
+    }
+
+    fn latest_price(&self, symbol: &str) -> Result<Decimal, PriceError> {
+        let provider = yahoo::YahooConnector::new().map_err(unavailable)?;
+        let response = provider.get_latest_quotes(symbol, "1d").map_err(unavailable)?;
+        let quote = response.last_quote().map_err(unavailable)?;
+        let price_f64 = quote.close;
+        let price = Decimal::from_f64(price_f64)
+            .ok_or_else(|| PriceError::Unavailable(format!("Failed to transform {} to Decimal", price_f64)))?;
+        Ok(price.round_dp(2))
+    }
+
+    fn history(&self, symbol: &str, period: &str, interval: &str) -> Result<Vec<Quote>, PriceError> {
+        let provider = yahoo::YahooConnector::new().map_err(unavailable)?;
+        let response = provider.get_quote_range(symbol, interval, period).map_err(unavailable)?;
+        response.quotes().map_err(unavailable)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Fixture {
+    // lower-cased search term -> symbol
+    #[serde(default)]
+    pub symbols: HashMap<String, String>,
+    // each call returns the next price, the last one repeats
+    #[serde(default)]
+    pub prices: HashMap<String, Vec<Decimal>>,
+    #[serde(default)]
+    pub history: HashMap<String, Vec<Quote>>,
+}
+
+// deterministic offline provider for tests and local runs
+#[derive(Default)]
+pub struct ScriptedProvider {
+    fixture: Fixture,
+    cursor: Mutex<HashMap<String, usize>>,
+}
+
+impl ScriptedProvider {
+    pub fn new(fixture: Fixture) -> Self {
+        ScriptedProvider { fixture, cursor: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn from_fixture(path: &str) -> Result<Self, String> {
+        Ok(ScriptedProvider::new(get_map(path)?))
+    }
+
+    pub fn with_symbol(mut self, name: &str, symbol: &str) -> Self {
+        self.fixture.symbols.insert(name.to_lowercase(), symbol.to_string());
+        self
+    }
+
+    pub fn with_prices(mut self, symbol: &str, prices: Vec<Decimal>) -> Self {
+        self.fixture.prices.insert(symbol.to_string(), prices);
+        self
+    }
+
+    pub fn with_history(mut self, symbol: &str, quotes: Vec<Quote>) -> Self {
+        self.fixture.history.insert(symbol.to_string(), quotes);
+        self
+    }
+}
+
+impl PriceProvider for ScriptedProvider {
+    fn search_symbol(&self, name: &str) -> Result<String, PriceError> {
+        if let Some(symbol) = self.fixture.symbols.get(&name.to_lowercase()) {
+            return Ok(symbol.clone());
+        }
+        let upper = name.to_uppercase();
+        if self.fixture.prices.contains_key(&upper) || self.fixture.history.contains_key(&upper) {
+            return Ok(upper);
+        }
+        Err(PriceError::NotFound(name.to_string()))
+    }
+
+    fn latest_price(&self, symbol: &str) -> Result<Decimal, PriceError> {
+        let prices = match self.fixture.prices.get(symbol) {
+            Some(prices) if !prices.is_empty() => prices,
+            _ => return Err(PriceError::NotFound(symbol.to_string())),
+        };
+        let mut cursor = self.cursor.lock().unwrap_or_else(|e| e.into_inner());
+        let index = cursor.entry(symbol.to_string()).or_insert(0);
+        let price = prices[(*index).min(prices.len() - 1)];
+        *index += 1;
+        Ok(price)
+    }
+
+    fn history(&self, symbol: &str, _period: &str, _interval: &str) -> Result<Vec<Quote>, PriceError> {
+        self.fixture.history.get(symbol)
+            .cloned()
+            .ok_or_else(|| PriceError::NotFound(symbol.to_string()))
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use axum::{extract::{Json, State}, response::IntoResponse};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::json;
+use structure::{AccountAuth, ClosedPosition};
+use storage::SharedStorage;
+use auth::Verified;
+
+#[derive(Serialize)]
+pub struct TradingStats {
+    pub trades: usize,
+    pub wins: usize,
+    pub losses: usize,
+    // share of closed positions with a positive earning, 0 to 1
+    pub win_rate: Decimal,
+    pub realized_pnl: Decimal,
+    pub best_trade: Option<ClosedPosition>,
+    pub worst_trade: Option<ClosedPosition>,
+    pub average_holding_secs: i64,
+}
+
+pub fn trading_stats(closed: &[ClosedPosition]) -> TradingStats {
+    let trades = closed.len();
+    let wins = closed.iter().filter(|c| c.earning > Decimal::ZERO).count();
+    let losses = closed.iter().filter(|c| c.earning < Decimal::ZERO).count();
+    let win_rate = if trades == 0 {
+        Decimal::ZERO
+    } else {
+        (Decimal::from(wins) / Decimal::from(trades)).round_dp(4)
+    };
+    let holding: i64 = closed.iter().map(|c| c.closed_at - c.opened_at).sum();
+
+    TradingStats {
+        trades,
+        wins,
+        losses,
+        win_rate,
+        realized_pnl: closed.iter().map(|c| c.earning).sum(),
+        best_trade: closed.iter().max_by_key(|c| c.earning).cloned(),
+        worst_trade: closed.iter().min_by_key(|c| c.earning).cloned(),
+        average_holding_secs: if trades == 0 { 0 } else { holding / trades as i64 },
+    }
+}
+
+pub async fn get_trading_stats(State(storage): State<SharedStorage>, Verified(id): Verified<AccountAuth>) -> impl IntoResponse {
+    let closed_map: HashMap<String, Vec<ClosedPosition>> = match storage.load_closed_of(&id.card_holder) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let closed = closed_map.get(&id.card_holder).map(Vec::as_slice).unwrap_or_default();
+    (StatusCode::OK, Json(json!(trading_stats(closed)))).into_response()
+}
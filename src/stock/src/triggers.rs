@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use axum::{extract::{Json, State}, response::IntoResponse};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use serde_json::json;
+use structure::{SetTriggers, Side, StockHold};
+use storage::{Changes, SharedLocks, SharedStorage};
+use auth::Verified;
+use crate::cache::env_secs;
+use crate::{check_triggers, close_hold_if, get_stock_price, price_error_status, quote_symbols, SharedQuotes};
+
+pub async fn set_triggers(State(storage): State<SharedStorage>, State(locks): State<SharedLocks>, State(quotes): State<SharedQuotes>, Verified(triggers): Verified<SetTriggers>) -> impl IntoResponse {
+    let _account = locks.account(&triggers.card_holder).await;
+    // fetched before taking the store lock so a slow quote doesn't block other accounts
+    let quote = match get_stock_price(&quotes, triggers.symbol.as_str()).await {
+        Ok(q) => q,
+        Err(e) => {
+            println!("Failed to get price: {}", e);
+            return (price_error_status(&e), "Failed to get price!").into_response();
+        }
+    };
+
+    let _store = locks.store().await;
+    let mut stock_map: HashMap<String, Vec<StockHold>> = match storage.load_holdings_of(&triggers.card_holder) {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("Error： {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+        }
+    };
+
+    let hold = stock_map.get_mut(&triggers.card_holder)
+        .and_then(|holds| holds.iter_mut().find(|s| s.timestamp == triggers.timestamp && s.stock.symbol == triggers.symbol));
+    let Some(hold) = hold else {
+        return (StatusCode::BAD_REQUEST, "No stock holdings found").into_response();
+    };
+
+    if let Err(e) = check_triggers(hold.stock.buy_type, quote.price, triggers.stop_loss, triggers.take_profit) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    hold.stop_loss = triggers.stop_loss;
+    hold.take_profit = triggers.take_profit;
+    let hold = hold.clone();
+
+    let changes = Changes { holdings: Some(&stock_map), ..Changes::default() };
+    if let Err(e) = storage.save_changes(changes) {
+        println!("Error in writing triggers: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response();
+    }
+
+    (StatusCode::OK, Json(json!(hold))).into_response()
+}
+
+// TRIGGER_CHECK_INTERVAL in seconds, default 5
+pub fn trigger_interval_from_env() -> Result<Duration, String> {
+    env_secs("TRIGGER_CHECK_INTERVAL", 5)
+}
+
+// background task spawned from main, never returns
+pub async fn run_trigger_watcher(storage: SharedStorage, locks: SharedLocks, quotes: SharedQuotes, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = close_triggered_positions(&storage, &locks, &quotes).await {
+            eprintln!("Trigger check failed： {}", e);
+        }
+    }
+}
+
+// the reason recorded on the closing trade, or None while the price is between the triggers
+fn triggered(hold: &StockHold, price: Decimal) -> Option<&'static str> {
+    let long = hold.stock.buy_type == Side::Long;
+    let hit = |limit: Option<Decimal>, above: bool| limit.is_some_and(|l| if above { price >= l } else { price <= l });
+    if hit(hold.stop_loss, !long) {
+        Some("stop_loss")
+    } else if hit(hold.take_profit, long) {
+        Some("take_profit")
+    } else {
+        None
+    }
+}
+
+// one pass over every position with a trigger, sold at the market price like /sell_stock
+pub async fn close_triggered_positions(storage: &SharedStorage, locks: &SharedLocks, quotes: &SharedQuotes) -> Result<usize, String> {
+    let watched: Vec<(String, StockHold)> = storage.load_holdings()?
+        .into_iter()
+        .flat_map(|(card_holder, holds)| holds.into_iter().map(move |hold| (card_holder.clone(), hold)))
+        .filter(|(_, hold)| hold.stop_loss.is_some() || hold.take_profit.is_some())
+        .collect();
+
+    let prices = quote_symbols(quotes, watched.iter().map(|(_, hold)| hold.stock.symbol.clone()).collect()).await;
+    let mut closed = 0;
+    for (card_holder, hold) in watched {
+        let Some(&price) = prices.get(&hold.stock.symbol) else { continue };
+        if triggered(&hold, price).is_none() {
+            continue;
+        }
+
+        let _account = locks.account(&card_holder).await;
+        let _store = locks.store().await;
+        if close_hold_if(storage, &card_holder, hold.timestamp, &hold.stock.symbol, price, |hold| triggered(hold, price))? {
+            closed += 1;
+        }
+    }
+    Ok(closed)
+}
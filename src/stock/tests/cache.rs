@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use axum::extract::{Json, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use yahoo_finance_api::Quote;
+use structure::Symbol;
+use stock::{get_last_price, PriceCache, PriceError, PriceProvider, ScriptedProvider, SharedQuotes};
+
+// counts the calls that would go out to the market data source
+struct Counting {
+    inner: ScriptedProvider,
+    searches: AtomicUsize,
+    quotes: AtomicUsize,
+    // how long each price takes to come back
+    delay: Duration,
+}
+
+fn counting(inner: ScriptedProvider, delay: Duration) -> Arc<Counting> {
+    Arc::new(Counting { inner, searches: AtomicUsize::new(0), quotes: AtomicUsize::new(0), delay })
+}
+
+fn prices(prices: &[i64]) -> ScriptedProvider {
+    ScriptedProvider::default().with_prices("AAPL", prices.iter().map(|p| Decimal::from(*p)).collect())
+}
+
+impl PriceProvider for Counting {
+    fn search_symbol(&self, name: &str) -> Result<String, PriceError> {
+        self.searches.fetch_add(1, Ordering::SeqCst);
+        self.inner.search_symbol(name)
+    }
+
+    fn latest_price(&self, symbol: &str) -> Result<Decimal, PriceError> {
+        self.quotes.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(self.delay);
+        self.inner.latest_price(symbol)
+    }
+
+    fn history(&self, symbol: &str, period: &str, interval: &str) -> Result<Vec<Quote>, PriceError> {
+        self.inner.history(symbol, period, interval)
+    }
+}
+
+#[tokio::test]
+async fn get_price_on_a_cold_cache_searches_once_and_quotes_once() {
+    let provider = counting(ScriptedProvider::default().with_symbol("apple", "AAPL").with_prices("AAPL", vec![Decimal::from(190)]), Duration::ZERO);
+    let quotes = SharedQuotes::new(PriceCache::new(provider.clone(), Duration::from_secs(60), Duration::from_secs(60), Duration::ZERO));
+
+    for _ in 0..2 {
+        let response = get_last_price(State(quotes.clone()), Json(Symbol { symbol: String::from("apple") })).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    assert_eq!(provider.searches.load(Ordering::SeqCst), 1);
+    assert_eq!(provider.quotes.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn stale_price_is_served_while_it_refreshes() {
+    let provider = counting(prices(&[100, 110]), Duration::ZERO);
+    let quotes = SharedQuotes::new(PriceCache::new(provider.clone(), Duration::from_secs(60), Duration::from_millis(50), Duration::from_secs(60)));
+
+    let first = quotes.quote_symbol("AAPL").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // past the fresh window, the old quote comes back untouched and a refresh starts
+    let stale = quotes.quote_symbol("AAPL").await.unwrap();
+    assert_eq!(stale.price, Decimal::from(100));
+    assert_eq!(stale.quoted_at, first.quoted_at);
+
+    for _ in 0..50 {
+        if quotes.quote_symbol("AAPL").await.unwrap().price == Decimal::from(110) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(quotes.quote_symbol("AAPL").await.unwrap().price, Decimal::from(110));
+    assert_eq!(provider.quotes.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn price_past_the_stale_window_is_fetched_again() {
+    let provider = counting(prices(&[100, 110]), Duration::ZERO);
+    let quotes = SharedQuotes::new(PriceCache::new(provider.clone(), Duration::from_secs(60), Duration::from_millis(20), Duration::from_millis(20)));
+
+    assert_eq!(quotes.quote_symbol("AAPL").await.unwrap().price, Decimal::from(100));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(quotes.quote_symbol("AAPL").await.unwrap().price, Decimal::from(110));
+    assert_eq!(provider.quotes.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_misses_for_a_symbol_ask_the_provider_once() {
+    let provider = counting(prices(&[100, 110]), Duration::from_millis(100));
+    let quotes = SharedQuotes::new(PriceCache::new(provider.clone(), Duration::from_secs(60), Duration::from_secs(60), Duration::ZERO));
+
+    let requests: Vec<_> = (0..5)
+        .map(|_| {
+            let quotes = quotes.clone();
+            tokio::spawn(async move { quotes.quote_symbol("AAPL").await.unwrap().price })
+        })
+        .collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), Decimal::from(100));
+    }
+    assert_eq!(provider.quotes.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,88 @@
+// shared by the stock integration tests, each of them only uses part of it
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::time::Duration;
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use rust_decimal::Decimal;
+use structure::{BuyStock, Hand, Leverage, SellStock, Side};
+use storage::{IdempotencyStore, SharedIdempotency, SharedLocks, SharedStorage};
+use storage::test_util::shared_storage;
+use auth::Verified;
+use stock::{buy_stock, sell_stock, PriceCache, ScriptedProvider, SharedPrices, SharedQuotes};
+
+pub struct Bank {
+    pub storage: SharedStorage,
+    pub locks: SharedLocks,
+    pub idempotency: SharedIdempotency,
+    pub quotes: SharedQuotes,
+}
+
+// one Classic card holding `balance`, prices are never cached so every quote asks `provider`
+pub fn bank_with(card_holder: &str, balance: i64, provider: SharedPrices) -> Bank {
+    Bank {
+        storage: shared_storage(&[(card_holder, balance)]),
+        locks: SharedLocks::default(),
+        idempotency: SharedIdempotency::new(IdempotencyStore::default()),
+        quotes: SharedQuotes::new(PriceCache::new(provider, Duration::from_secs(60), Duration::ZERO, Duration::ZERO)),
+    }
+}
+
+// every quote of AAPL fetches the next of `prices`
+pub fn bank(card_holder: &str, balance: i64, prices: &[i64]) -> Bank {
+    let provider = ScriptedProvider::default()
+        .with_prices("AAPL", prices.iter().map(|p| Decimal::from(*p)).collect());
+    bank_with(card_holder, balance, Arc::new(provider))
+}
+
+pub fn buy(card_holder: &str, symbol: &str, buy_type: Side, hand: i64) -> BuyStock {
+    BuyStock {
+        buy_type,
+        symbol: symbol.to_string(),
+        hand: Hand::try_from(Decimal::from(hand)).unwrap(),
+        leverage: Leverage::try_from(Decimal::from(100)).unwrap(),
+        stop_loss: None,
+        take_profit: None,
+        token: String::new(),
+        target: String::from("discord"),
+        card_holder: card_holder.to_string(),
+        idempotency_key: None,
+    }
+}
+
+pub fn sell(card_holder: &str, symbol: &str, hand: Option<i64>) -> SellStock {
+    SellStock {
+        symbol: symbol.to_string(),
+        timestamp: None,
+        hand: hand.map(Decimal::from),
+        token: String::new(),
+        target: String::from("discord"),
+        card_holder: card_holder.to_string(),
+        idempotency_key: None,
+    }
+}
+
+impl Bank {
+    pub async fn buy(&self, body: BuyStock) -> Response {
+        buy_stock(State(self.storage.clone()), State(self.locks.clone()), State(self.idempotency.clone()), State(self.quotes.clone()), Verified(body))
+            .await
+            .into_response()
+    }
+
+    pub async fn sell(&self, body: SellStock) -> Response {
+        sell_stock(State(self.storage.clone()), State(self.locks.clone()), State(self.idempotency.clone()), State(self.quotes.clone()), Verified(body))
+            .await
+            .into_response()
+    }
+
+    pub fn balance(&self, card_holder: &str) -> Decimal {
+        self.storage.load_accounts_of(&[card_holder]).unwrap().into_values().next().unwrap().balance
+    }
+
+    pub fn held_hands(&self, card_holder: &str) -> Vec<Decimal> {
+        let holdings = self.storage.load_holdings_of(card_holder).unwrap();
+        holdings.get(card_holder).into_iter().flatten().map(|hold| hold.stock.hand).collect()
+    }
+}
+
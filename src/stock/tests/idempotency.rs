@@ -0,0 +1,46 @@
+mod common;
+
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use structure::Side;
+use storage::{IdempotencyStore, SharedIdempotency};
+use storage::test_util::json_body;
+use common::{bank, buy, sell};
+
+#[tokio::test]
+async fn buy_and_sell_retried_with_the_same_key_are_applied_once() {
+    // replays never ask for a price, so the sale gets the second one
+    let mut bank = bank("alice", 1000, &[100, 110]);
+    let mut body = buy("alice", "AAPL", Side::Long, 1);
+    body.idempotency_key = Some(String::from("buy-1"));
+    let first = json_body(bank.buy(body).await).await;
+    assert_eq!(bank.balance("alice"), Decimal::from(900));
+
+    // a fresh store is what the server has after a restart
+    bank.idempotency = SharedIdempotency::new(IdempotencyStore::default());
+    let mut body = buy("alice", "AAPL", Side::Long, 1);
+    body.idempotency_key = Some(String::from("buy-1"));
+    let retry = bank.buy(body).await;
+    assert_eq!(retry.status(), StatusCode::OK);
+    assert_eq!(retry.headers()["idempotent-replay"], "true");
+    assert_eq!(json_body(retry).await, first);
+    assert_eq!(bank.balance("alice"), Decimal::from(900));
+    assert_eq!(bank.held_hands("alice"), vec![Decimal::ONE]);
+
+    let mut body = sell("alice", "AAPL", Some(1));
+    body.idempotency_key = Some(String::from("sell-1"));
+    let first = json_body(bank.sell(body).await).await;
+    assert_eq!(bank.balance("alice"), Decimal::from(1010));
+
+    bank.idempotency = SharedIdempotency::new(IdempotencyStore::default());
+    let mut body = sell("alice", "AAPL", Some(1));
+    body.idempotency_key = Some(String::from("sell-1"));
+    let retry = bank.sell(body).await;
+    assert_eq!(retry.status(), StatusCode::OK);
+    assert_eq!(json_body(retry).await, first);
+    assert_eq!(bank.balance("alice"), Decimal::from(1010));
+    assert!(bank.held_hands("alice").is_empty());
+
+    // one buy and one sell
+    assert_eq!(bank.storage.load_trades().unwrap().len(), 2);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use structure::{Side, Stock, StockHold};
+use stock::{mark_to_market, SharedMargin};
+use common::bank;
+
+fn hold(leverage: i64) -> StockHold {
+    StockHold {
+        timestamp: 10,
+        stock: Stock {
+            buy_type: Side::Long,
+            symbol: String::from("AAPL"),
+            hand: Decimal::ONE,
+            leverage: Decimal::from(leverage),
+            price: Decimal::from(100),
+        },
+        stop_loss: None,
+        take_profit: None,
+    }
+}
+
+#[tokio::test]
+async fn small_moves_keep_a_position_above_maintenance() {
+    // a 1x Long keeps 99 of its principal of 100 after a 1% drop
+    let bank = bank("alice", 0, &[99]);
+    bank.storage.save_holdings(&HashMap::from([(String::from("alice"), vec![hold(100)])])).unwrap();
+    let margin = SharedMargin::default();
+
+    assert_eq!(mark_to_market(&bank.storage, &bank.locks, &bank.quotes, &margin).await, Ok(0));
+
+    let positions = margin.positions("alice");
+    assert_eq!(positions[0].level, "ok");
+    assert_eq!(positions[0].equity, Decimal::from(99));
+    assert_eq!(bank.held_hands("alice"), vec![Decimal::ONE]);
+}
+
+#[tokio::test]
+async fn position_below_maintenance_is_liquidated() {
+    // a 1x position is warned below 40% of its principal and sold below 25%
+    let bank = bank("alice", 0, &[35, 20]);
+    bank.storage.save_holdings(&HashMap::from([(String::from("alice"), vec![hold(100)])])).unwrap();
+    let margin = SharedMargin::default();
+
+    assert_eq!(mark_to_market(&bank.storage, &bank.locks, &bank.quotes, &margin).await, Ok(0));
+    assert_eq!(margin.positions("alice")[0].level, "warning");
+
+    assert_eq!(mark_to_market(&bank.storage, &bank.locks, &bank.quotes, &margin).await, Ok(1));
+    assert_eq!(margin.positions("alice")[0].level, "liquidated");
+
+    // what is left of the principal is paid back
+    assert!(bank.held_hands("alice").is_empty());
+    assert_eq!(bank.balance("alice"), Decimal::from(20));
+    let closed = bank.storage.load_closed_of("alice").unwrap();
+    assert_eq!(closed["alice"][0].reason.as_deref(), Some("liquidation"));
+    assert_eq!(closed["alice"][0].earning, Decimal::from(-80));
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use std::collections::HashMap;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use structure::{AccountAuth, Hand, Leverage, LimitOrder, OrderSide, OrderStatus, PlaceOrder, Side, Stock, StockHold};
+use function::check_balances;
+use storage::open_ledger;
+use storage::test_util::json_body;
+use auth::Verified;
+use stock::{get_portfolio, match_orders, place_order};
+use common::bank;
+
+fn hold(leverage: i64) -> StockHold {
+    StockHold {
+        timestamp: 10,
+        stock: Stock {
+            buy_type: Side::Long,
+            symbol: String::from("AAPL"),
+            hand: Decimal::ONE,
+            leverage: Decimal::from(leverage),
+            price: Decimal::from(100),
+        },
+        stop_loss: None,
+        take_profit: None,
+    }
+}
+
+fn sell_order(limit_price: i64) -> LimitOrder {
+    LimitOrder {
+        card_holder: String::from("alice"),
+        side: OrderSide::Sell,
+        buy_type: Side::Long,
+        symbol: String::from("AAPL"),
+        hand: Decimal::ONE,
+        leverage: Decimal::from(100),
+        limit_price: Decimal::from(limit_price),
+        reserved: Decimal::ZERO,
+        hold_timestamp: Some(10),
+        created_at: 10,
+        status: OrderStatus::Open,
+        filled_price: None,
+        closed_at: None,
+    }
+}
+
+#[tokio::test]
+async fn sell_order_fills_at_the_market_once_it_crosses() {
+    let bank = bank("alice", 0, &[105, 120]);
+    bank.storage.save_holdings(&HashMap::from([(String::from("alice"), vec![hold(100)])])).unwrap();
+    bank.storage.save_orders(&HashMap::from([(1, sell_order(110))])).unwrap();
+
+    assert_eq!(match_orders(&bank.storage, &bank.locks, &bank.quotes).await, Ok(0));
+    assert_eq!(match_orders(&bank.storage, &bank.locks, &bank.quotes).await, Ok(1));
+
+    // the market went past the limit, the card gets the better price
+    let order = &bank.storage.load_orders().unwrap()[&1];
+    assert_eq!(order.status, OrderStatus::Filled);
+    assert_eq!(order.filled_price, Some(Decimal::from(120)));
+    assert!(bank.held_hands("alice").is_empty());
+    assert_eq!(bank.balance("alice"), Decimal::from(120));
+}
+
+#[tokio::test]
+async fn buy_order_below_its_limit_refunds_what_it_saved() {
+    let bank = bank("alice", 1000, &[100]);
+    open_ledger(bank.storage.as_ref(), 0).unwrap();
+    let order = PlaceOrder {
+        side: OrderSide::Buy,
+        symbol: String::from("AAPL"),
+        limit_price: Decimal::from(120),
+        buy_type: Some(Side::Long),
+        hand: Some(Hand::try_from(Decimal::ONE).unwrap()),
+        leverage: Some(Leverage::try_from(Decimal::from(100)).unwrap()),
+        timestamp: None,
+        token: String::new(),
+        target: String::from("discord"),
+        card_holder: String::from("alice"),
+        idempotency_key: None,
+    };
+    let response = place_order(State(bank.storage.clone()), State(bank.locks.clone()), State(bank.idempotency.clone()), State(bank.quotes.clone()), Verified(order)).await.into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(bank.balance("alice"), Decimal::from(880));
+
+    assert_eq!(match_orders(&bank.storage, &bank.locks, &bank.quotes).await, Ok(1));
+
+    let order = bank.storage.load_orders().unwrap().into_values().next().unwrap();
+    assert_eq!(order.filled_price, Some(Decimal::from(100)));
+    let holdings = bank.storage.load_holdings_of("alice").unwrap();
+    assert_eq!(holdings["alice"][0].stock.price, Decimal::from(100));
+    assert_eq!(bank.balance("alice"), Decimal::from(900));
+
+    let card_map = bank.storage.load_accounts().unwrap();
+    assert!(check_balances(&card_map, &bank.storage.load_ledger().unwrap()).is_empty());
+}
+
+#[tokio::test]
+async fn sell_order_of_an_unsellable_hold_is_cancelled_and_the_hold_kept() {
+    let bank = bank("alice", 0, &[120]);
+    // stored before leverage was validated
+    bank.storage.save_holdings(&HashMap::from([(String::from("alice"), vec![hold(0)])])).unwrap();
+    bank.storage.save_orders(&HashMap::from([(1, sell_order(110))])).unwrap();
+
+    assert_eq!(match_orders(&bank.storage, &bank.locks, &bank.quotes).await, Ok(0));
+
+    assert_eq!(bank.storage.load_orders().unwrap()[&1].status, OrderStatus::Cancelled);
+    assert_eq!(bank.held_hands("alice"), vec![Decimal::ONE]);
+    assert_eq!(bank.balance("alice"), Decimal::ZERO);
+    assert!(bank.storage.load_trades().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn open_buy_order_funds_count_toward_equity() {
+    let bank = bank("alice", 900, &[]);
+    let buy_order = LimitOrder {
+        side: OrderSide::Buy,
+        reserved: Decimal::from(100),
+        hold_timestamp: None,
+        ..sell_order(90)
+    };
+    bank.storage.save_orders(&HashMap::from([(1, buy_order)])).unwrap();
+
+    let id = AccountAuth { card_holder: String::from("alice"), token: String::new(), target: String::from("discord") };
+    let response = get_portfolio(State(bank.storage.clone()), State(bank.quotes.clone()), Verified(id)).await.into_response();
+
+    let body = json_body(response).await;
+    assert_eq!(body["balance"], "900");
+    assert_eq!(body["reserved"], "100");
+    assert_eq!(body["equity"], "1000");
+}
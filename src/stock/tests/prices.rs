@@ -0,0 +1,107 @@
+mod common;
+
+use std::sync::Arc;
+use axum::extract::{Json, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use yahoo_finance_api::Quote;
+use structure::{Leverage, Side, Symbol};
+use storage::test_util::json_body;
+use stock::{get_last_price, PriceError, PriceProvider, ScriptedProvider};
+use common::{bank, bank_with, buy, sell};
+
+// a market data source that can't be reached
+struct Offline;
+
+impl PriceProvider for Offline {
+    fn search_symbol(&self, _name: &str) -> Result<String, PriceError> {
+        Err(PriceError::Unavailable(String::from("offline")))
+    }
+
+    fn latest_price(&self, _symbol: &str) -> Result<Decimal, PriceError> {
+        Err(PriceError::Unavailable(String::from("offline")))
+    }
+
+    fn history(&self, _symbol: &str, _period: &str, _interval: &str) -> Result<Vec<Quote>, PriceError> {
+        Err(PriceError::Unavailable(String::from("offline")))
+    }
+}
+
+#[tokio::test]
+async fn long_position_earns_when_the_price_rises() {
+    let bank = bank("alice", 1000, &[100, 110]);
+    assert_eq!(bank.buy(buy("alice", "AAPL", Side::Long, 1)).await.status(), StatusCode::OK);
+    assert_eq!(bank.balance("alice"), Decimal::from(900));
+
+    let response = bank.sell(sell("alice", "AAPL", Some(1))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(body["price"], "110");
+    assert_eq!(body["earning"], "10");
+
+    // the principal of 100 comes back with the earning
+    assert_eq!(bank.balance("alice"), Decimal::from(1010));
+    assert!(bank.held_hands("alice").is_empty());
+}
+
+#[tokio::test]
+async fn short_position_earns_when_the_price_falls() {
+    let bank = bank("alice", 1000, &[100, 95]);
+    assert_eq!(bank.buy(buy("alice", "AAPL", Side::Short, 2)).await.status(), StatusCode::OK);
+
+    let body = json_body(bank.sell(sell("alice", "AAPL", Some(2))).await).await;
+    assert_eq!(body["earning"], "10");
+    assert_eq!(bank.balance("alice"), Decimal::from(1010));
+}
+
+#[tokio::test]
+async fn a_loss_never_takes_the_balance_below_zero() {
+    let bank = bank("alice", 60, &[100, 20]);
+    let mut body = buy("alice", "AAPL", Side::Long, 1);
+    body.leverage = Leverage::try_from(Decimal::from(200)).unwrap();
+    // a principal of 50 at twice the exposure
+    assert_eq!(bank.buy(body).await.status(), StatusCode::OK);
+    assert_eq!(bank.balance("alice"), Decimal::from(10));
+
+    // a loss of 160 takes the principal and the 10 left, not more
+    let body = json_body(bank.sell(sell("alice", "AAPL", Some(1))).await).await;
+    assert_eq!(body["earning"], "-60");
+    assert_eq!(bank.balance("alice"), Decimal::ZERO);
+}
+
+#[tokio::test]
+async fn unknown_symbol_is_not_found() {
+    let bank = bank("alice", 1000, &[100]);
+
+    assert_eq!(bank.buy(buy("alice", "NOPE", Side::Long, 1)).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(bank.sell(sell("alice", "NOPE", Some(1))).await.status(), StatusCode::NOT_FOUND);
+    let response = get_last_price(State(bank.quotes.clone()), Json(Symbol { symbol: String::from("nope") })).await.into_response();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(bank.balance("alice"), Decimal::from(1000));
+}
+
+#[tokio::test]
+async fn unreachable_provider_is_unavailable() {
+    let bank = bank_with("alice", 1000, Arc::new(Offline));
+
+    assert_eq!(bank.buy(buy("alice", "AAPL", Side::Long, 1)).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(bank.sell(sell("alice", "AAPL", Some(1))).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = get_last_price(State(bank.quotes.clone()), Json(Symbol { symbol: String::from("AAPL") })).await.into_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(bank.balance("alice"), Decimal::from(1000));
+}
+
+#[tokio::test]
+async fn get_price_resolves_names_through_the_fixture() {
+    let provider = ScriptedProvider::default()
+        .with_symbol("Apple", "AAPL")
+        .with_prices("AAPL", vec![Decimal::new(18950, 2)]);
+    let bank = bank_with("alice", 0, Arc::new(provider));
+
+    let response = get_last_price(State(bank.quotes.clone()), Json(Symbol { symbol: String::from("apple") })).await.into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(body["symbol"], "AAPL");
+    assert_eq!(body["price"], "189.50");
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use structure::Side;
+use function::{account_balances, card_account, STOCK_CLEARING};
+use storage::test_util::json_body;
+use common::{bank, buy, sell};
+
+#[tokio::test]
+async fn buy_takes_the_principal_into_stock_clearing() {
+    let bank = bank("alice", 1000, &[100]);
+
+    let response = bank.buy(buy("alice", "AAPL", Side::Long, 3)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["cost"], "300");
+
+    assert_eq!(bank.balance("alice"), Decimal::from(700));
+    assert_eq!(bank.held_hands("alice"), vec![Decimal::from(3)]);
+    let balances = account_balances(&bank.storage.load_ledger().unwrap());
+    assert_eq!(balances[&card_account("alice")], Decimal::from(-300));
+    assert_eq!(balances[STOCK_CLEARING], Decimal::from(300));
+}
+
+#[tokio::test]
+async fn buy_beyond_balance_is_refused() {
+    let bank = bank("alice", 250, &[100]);
+
+    let response = bank.buy(buy("alice", "AAPL", Side::Long, 3)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(bank.balance("alice"), Decimal::from(250));
+    assert!(bank.held_hands("alice").is_empty());
+    assert!(bank.storage.load_trades().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn unknown_card_can_neither_buy_nor_sell() {
+    let bank = bank("alice", 1000, &[100]);
+
+    assert_eq!(bank.buy(buy("bob", "AAPL", Side::Long, 1)).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(bank.sell(sell("bob", "AAPL", Some(1))).await.status(), StatusCode::BAD_REQUEST);
+    assert!(bank.storage.load_trades().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn partial_sell_keeps_the_rest_of_the_position() {
+    let bank = bank("alice", 1000, &[100, 100]);
+    assert_eq!(bank.buy(buy("alice", "AAPL", Side::Long, 3)).await.status(), StatusCode::OK);
+
+    let response = bank.sell(sell("alice", "AAPL", Some(1))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    assert_eq!(body["hand"], "1");
+    assert_eq!(body["earning"], "0");
+
+    // one hand of principal comes back at the buy price
+    assert_eq!(bank.balance("alice"), Decimal::from(800));
+    assert_eq!(bank.held_hands("alice"), vec![Decimal::from(2)]);
+    assert_eq!(bank.storage.load_closed_of("alice").unwrap()["alice"].len(), 1);
+}
+
+#[tokio::test]
+async fn selling_more_than_held_is_refused() {
+    let bank = bank("alice", 1000, &[100, 100]);
+    assert_eq!(bank.buy(buy("alice", "AAPL", Side::Long, 2)).await.status(), StatusCode::OK);
+
+    assert_eq!(bank.sell(sell("alice", "AAPL", Some(5))).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(bank.balance("alice"), Decimal::from(800));
+    assert_eq!(bank.held_hands("alice"), vec![Decimal::from(2)]);
+}
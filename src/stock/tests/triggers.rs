@@ -0,0 +1,56 @@
+mod common;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use rust_decimal::Decimal;
+use structure::{BuyStock, SetTriggers, Side};
+use auth::Verified;
+use stock::set_triggers;
+use common::{bank, buy};
+
+fn with_triggers(body: BuyStock, stop_loss: Option<i64>, take_profit: Option<i64>) -> BuyStock {
+    BuyStock { stop_loss: stop_loss.map(Decimal::from), take_profit: take_profit.map(Decimal::from), ..body }
+}
+
+#[tokio::test]
+async fn triggers_on_the_wrong_side_of_the_price_are_refused() {
+    let bank = bank("alice", 1000, &[100]);
+
+    // a Long stop-loss above the market would sell on the next tick
+    let long = with_triggers(buy("alice", "AAPL", Side::Long, 1), Some(105), None);
+    assert_eq!(bank.buy(long).await.status(), StatusCode::BAD_REQUEST);
+    let short = with_triggers(buy("alice", "AAPL", Side::Short, 1), None, Some(105));
+    assert_eq!(bank.buy(short).await.status(), StatusCode::BAD_REQUEST);
+    assert!(bank.held_hands("alice").is_empty());
+
+    let short = with_triggers(buy("alice", "AAPL", Side::Short, 1), Some(105), Some(95));
+    assert_eq!(bank.buy(short).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn set_triggers_checks_against_the_current_price() {
+    let bank = bank("alice", 1000, &[100, 120]);
+    assert_eq!(bank.buy(buy("alice", "AAPL", Side::Long, 1)).await.status(), StatusCode::OK);
+    let timestamp = bank.storage.load_holdings_of("alice").unwrap()["alice"][0].timestamp;
+
+    let set = |stop_loss: i64, take_profit: i64| SetTriggers {
+        symbol: String::from("AAPL"),
+        timestamp,
+        stop_loss: Some(Decimal::from(stop_loss)),
+        take_profit: Some(Decimal::from(take_profit)),
+        token: String::new(),
+        target: String::from("discord"),
+        card_holder: String::from("alice"),
+    };
+
+    // the price has risen to 120, a stop-loss above the entry but below the market is fine
+    let response = set_triggers(State(bank.storage.clone()), State(bank.locks.clone()), State(bank.quotes.clone()), Verified(set(110, 130))).await.into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = set_triggers(State(bank.storage.clone()), State(bank.locks.clone()), State(bank.quotes.clone()), Verified(set(110, 115))).await.into_response();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let hold = &bank.storage.load_holdings_of("alice").unwrap()["alice"][0];
+    assert_eq!(hold.stop_loss, Some(Decimal::from(110)));
+    assert_eq!(hold.take_profit, Some(Decimal::from(130)));
+}
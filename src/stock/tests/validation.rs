@@ -0,0 +1,92 @@
+mod common;
+
+use std::sync::{Arc, RwLock};
+use axum::body::Body;
+use axum::extract::{FromRef, FromRequest, Request};
+use axum::http::StatusCode;
+use rust_decimal::Decimal;
+use structure::{BuyStock, Leverage, Side};
+use function::SharedKeys;
+use storage::{MemoryStorage, SharedStorage};
+use storage::test_util::{json_body, key_ring};
+use auth::Verified;
+use common::{bank, buy};
+
+#[derive(Clone)]
+struct Extract {
+    storage: SharedStorage,
+    keys: SharedKeys,
+}
+
+impl FromRef<Extract> for SharedStorage {
+    fn from_ref(state: &Extract) -> Self {
+        state.storage.clone()
+    }
+}
+
+impl FromRef<Extract> for SharedKeys {
+    fn from_ref(state: &Extract) -> Self {
+        state.keys.clone()
+    }
+}
+
+// what /buy_stock answers for `body` before any card is looked up
+async fn extract(body: &str) -> (StatusCode, serde_json::Value) {
+    let state = Extract {
+        storage: Arc::new(MemoryStorage::default()),
+        keys: SharedKeys::new(RwLock::new(key_ring(3600))),
+    };
+    let request = Request::builder()
+        .method("POST")
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    match Verified::<BuyStock>::from_request(request, &state).await {
+        Ok(_) => panic!("{} was accepted", body),
+        Err(response) => (response.status(), json_body(response).await),
+    }
+}
+
+fn body(buy_type: &str, hand: &str, leverage: &str) -> String {
+    format!(
+        r#"{{"buy_type": "{}", "symbol": "AAPL", "hand": {}, "leverage": {}, "token": "", "target": "discord", "card_holder": "alice"}}"#,
+        buy_type, hand, leverage
+    )
+}
+
+#[tokio::test]
+async fn unknown_buy_type_is_unprocessable() {
+    let (status, error) = extract(&body("long", "1", "100")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error["field"], "buy_type");
+}
+
+#[tokio::test]
+async fn hand_that_is_not_positive_is_unprocessable() {
+    for hand in ["0", "-1"] {
+        let (status, error) = extract(&body("Long", hand, "100")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["field"], "hand");
+    }
+}
+
+#[tokio::test]
+async fn zero_leverage_is_unprocessable() {
+    let (status, error) = extract(&body("Long", "1", "0")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error["field"], "leverage");
+}
+
+#[tokio::test]
+async fn leverage_over_the_card_limit_is_unprocessable() {
+    let bank = bank("alice", 100000, &[100]);
+    let mut body = buy("alice", "AAPL", Side::Long, 1);
+    // Classic cards allow at most 200
+    body.leverage = Leverage::try_from(Decimal::from(300)).unwrap();
+
+    let response = bank.buy(body).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(json_body(response).await["field"], "leverage");
+    assert_eq!(bank.balance("alice"), Decimal::from(100000));
+    assert!(bank.held_hands("alice").is_empty());
+}
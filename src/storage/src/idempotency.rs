@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum::body::{to_bytes, Body};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use structure::IdempotentResponse;
+use crate::Storage;
+
+pub type SharedIdempotency = Arc<IdempotencyStore>;
+
+// successful responses of money-moving requests, replayed when a client retries with the same key;
+// they are saved through `Storage` with the request's own changes, so a retry after a restart is still replayed
+pub struct IdempotencyStore {
+    window: Duration,
+}
+
+impl Default for IdempotencyStore {
+    fn default() -> Self {
+        IdempotencyStore::new(Duration::from_secs(86400))
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+impl IdempotencyStore {
+    pub fn new(window: Duration) -> Self {
+        IdempotencyStore { window }
+    }
+
+    // IDEMPOTENCY_WINDOW in seconds, one day by default
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("IDEMPOTENCY_WINDOW") {
+            Ok(secs) => secs.parse::<u64>()
+                .map(|secs| IdempotencyStore::new(Duration::from_secs(secs)))
+                .map_err(|e| format!("Invalid IDEMPOTENCY_WINDOW {} ：{}", secs, e)),
+            Err(_) => Ok(IdempotencyStore::default()),
+        }
+    }
+
+    // keys are scoped to a route and card holder so clients can't collide with each other
+    pub fn key(route: &str, card_holder: &str, idempotency_key: Option<&str>) -> Option<String> {
+        idempotency_key.map(|key| format!("{}:{}:{}", route, card_holder, key))
+    }
+
+    // responses stored before this time are no longer replayed
+    pub fn expired_before(&self) -> i64 {
+        now() - self.window.as_secs() as i64
+    }
+
+    // the stored response for `key`, or a 500 when it can't be looked up so the request isn't run twice
+    pub fn replay(&self, storage: &dyn Storage, key: Option<&str>) -> Option<Response> {
+        let stored = match storage.load_response(key?) {
+            Ok(stored) => stored?,
+            Err(e) => {
+                eprintln!("Error： {}", e);
+                return Some((StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response());
+            }
+        };
+        if stored.stored_at < self.expired_before() {
+            return None;
+        }
+
+        let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+        let mut response = (status, Body::from(stored.body)).into_response();
+        if let Some(content_type) = stored.content_type.and_then(|c| HeaderValue::from_str(&c).ok()) {
+            response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+        }
+        response.headers_mut().insert("idempotent-replay", HeaderValue::from_static("true"));
+        Some(response)
+    }
+
+    // buffers `response` into the row to pass as `Changes::responses`, None without a key;
+    // the response is handed back unchanged once the changes are saved
+    pub async fn capture(&self, key: Option<String>, response: Response) -> Result<(Option<HashMap<String, IdempotentResponse>>, Response), Response> {
+        let Some(key) = key else {
+            return Ok((None, response));
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = match to_bytes(body, usize::MAX).await.map_err(|e| e.to_string())
+            .and_then(|bytes| String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to buffer response for replay： {}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "Server error, please call admin fixing!").into_response());
+            }
+        };
+
+        let stored = IdempotentResponse {
+            status: parts.status.as_u16(),
+            content_type: parts.headers.get(header::CONTENT_TYPE).and_then(|c| c.to_str().ok()).map(String::from),
+            body: body.clone(),
+            stored_at: now(),
+        };
+        Ok((Some(HashMap::from([(key, stored)])), Response::from_parts(parts, Body::from(body))))
+    }
+}
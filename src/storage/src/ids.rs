@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use function::{IdFormat, Sequence};
+use crate::Storage;
+
+struct IdState {
+    last: i64,
+    // every id up to here may already be handed out, a restart continues after it
+    reserved: i64,
+}
+
+// hands out ids from memory and only writes a sequence when a new block of it is reserved
+#[derive(Default)]
+pub struct Sequences {
+    trade_format: IdFormat,
+    state: Mutex<HashMap<Sequence, IdState>>,
+}
+
+impl Sequences {
+    pub fn new(trade_format: IdFormat) -> Self {
+        Sequences { trade_format, state: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn next(&self, storage: &dyn Storage, sequence: Sequence) -> Result<i64, String> {
+        let mut states = self.state.lock().map_err(|e| e.to_string())?;
+        let state = match states.entry(sequence) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // ids saved before the sequence existed still count
+                let last = storage.load_sequence(sequence.name())?.max(highest_id(storage, sequence)?);
+                entry.insert(IdState { last, reserved: last })
+            }
+        };
+
+        let format = match sequence {
+            Sequence::Trade => self.trade_format,
+            Sequence::Order | Sequence::LedgerEntry => IdFormat::Sequential,
+        };
+        let id = format.next_after(state.last);
+        if id > state.reserved {
+            let reserved = id + format.block();
+            storage.save_sequence(sequence.name(), reserved)?;
+            state.reserved = reserved;
+        }
+        state.last = id;
+        Ok(id)
+    }
+}
+
+// the highest id already stored, read once on the first allocation of each sequence
+fn highest_id(storage: &dyn Storage, sequence: Sequence) -> Result<i64, String> {
+    match sequence {
+        Sequence::Trade => {
+            let trades = storage.load_trades()?.into_keys().max().unwrap_or(0);
+            // a card may still index a trade that is gone
+            let indexed = storage.load_accounts()?.values()
+                .flat_map(|data| data.transaction.iter().flat_map(|index| index.iter()))
+                .map(|trade| trade.trade_id)
+                .max()
+                .unwrap_or(0);
+            Ok(trades.max(indexed))
+        }
+        Sequence::Order => Ok(storage.load_orders()?.into_keys().max().unwrap_or(0)),
+        Sequence::LedgerEntry => Ok(storage.load_ledger()?.into_keys().max().unwrap_or(0)),
+    }
+}
@@ -0,0 +1,609 @@
+mod idempotency;
+mod ids;
+mod sqlite;
+// fixtures shared by the integration tests of every crate
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::{env, fs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{MutexGuard, OwnedMutexGuard};
+use structure::{CardInfo, ClosedPosition, IdempotentResponse, LedgerEntry, LimitOrder, OrderStatus, ReconciliationReport, TradeHistory, StockHold};
+use function::{account_balances, check_balances, get_map, open_balances, reconcile, write_json_to_file, write_atomic, IdFormat, IdSource, Sequence};
+
+pub use idempotency::{IdempotencyStore, SharedIdempotency};
+pub use ids::Sequences;
+pub use sqlite::SqliteStorage;
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+// every backend also hands out ids, see `Sequences`
+pub trait Storage: IdSource + Send + Sync {
+    // every save writes rows the way `save_changes` does: cards, trades, orders, closed positions and
+    // ledger entries are never deleted, but a saved card replaces its connections and a saved list of
+    // holdings replaces every hold of that card holder, so sold holds are gone;
+    // idempotent responses are only removed by `prune_responses`
+    fn load_accounts(&self) -> Result<HashMap<u64, CardInfo>, String>;
+    fn save_accounts(&self, accounts: &HashMap<u64, CardInfo>) -> Result<(), String>;
+    fn load_trades(&self) -> Result<HashMap<i64, TradeHistory>, String>;
+    fn save_trades(&self, trades: &HashMap<i64, TradeHistory>) -> Result<(), String>;
+    fn load_holdings(&self) -> Result<HashMap<String, Vec<StockHold>>, String>;
+    fn save_holdings(&self, holdings: &HashMap<String, Vec<StockHold>>) -> Result<(), String>;
+    fn load_orders(&self) -> Result<HashMap<i64, LimitOrder>, String>;
+    fn save_orders(&self, orders: &HashMap<i64, LimitOrder>) -> Result<(), String>;
+    fn load_closed(&self) -> Result<HashMap<String, Vec<ClosedPosition>>, String>;
+    fn save_closed(&self, closed: &HashMap<String, Vec<ClosedPosition>>) -> Result<(), String>;
+    fn load_ledger(&self) -> Result<HashMap<i64, LedgerEntry>, String>;
+    fn save_ledger(&self, ledger: &HashMap<i64, LedgerEntry>) -> Result<(), String>;
+    // the highest value reserved by a sequence, 0 before its first reservation
+    fn load_sequence(&self, name: &str) -> Result<i64, String>;
+    fn save_sequence(&self, name: &str, value: i64) -> Result<(), String>;
+    // the response stored under an idempotency key, see `IdempotencyStore`
+    fn load_response(&self, key: &str) -> Result<Option<IdempotentResponse>, String>;
+    // removes responses stored before `before`, returning how many
+    fn prune_responses(&self, before: i64) -> Result<usize, String>;
+    // writes every row in `changes` or none of them, see `Changes`
+    fn save_changes(&self, changes: Changes) -> Result<(), String>;
+
+    // row-level reads for requests that only touch a few cards, the defaults filter a full load
+    // and backends that can look rows up directly override them
+
+    // the cards of these card holders, a key may also be a card number
+    fn load_accounts_of(&self, keys: &[&str]) -> Result<HashMap<u64, CardInfo>, String> {
+        let accounts = self.load_accounts()?;
+        Ok(accounts.into_iter()
+            .filter(|(_, data)| keys.iter().any(|key| *key == data.card_holder || *key == data.card_number))
+            .collect())
+    }
+
+    fn load_trades_of(&self, ids: &[i64]) -> Result<HashMap<i64, TradeHistory>, String> {
+        let mut trades = self.load_trades()?;
+        Ok(ids.iter().filter_map(|id| trades.remove_entry(id)).collect())
+    }
+
+    fn load_holdings_of(&self, card_holder: &str) -> Result<HashMap<String, Vec<StockHold>>, String> {
+        let mut holdings = self.load_holdings()?;
+        Ok(holdings.remove_entry(card_holder).into_iter().collect())
+    }
+
+    fn load_orders_of(&self, card_holder: &str) -> Result<HashMap<i64, LimitOrder>, String> {
+        let orders = self.load_orders()?;
+        Ok(orders.into_iter().filter(|(_, order)| order.card_holder == card_holder).collect())
+    }
+
+    fn load_open_orders(&self) -> Result<HashMap<i64, LimitOrder>, String> {
+        let orders = self.load_orders()?;
+        Ok(orders.into_iter().filter(|(_, order)| order.status == OrderStatus::Open).collect())
+    }
+
+    fn load_closed_of(&self, card_holder: &str) -> Result<HashMap<String, Vec<ClosedPosition>>, String> {
+        let mut closed = self.load_closed()?;
+        Ok(closed.remove_entry(card_holder).into_iter().collect())
+    }
+
+    // finishes or discards an update interrupted by a crash, called once on startup
+    fn recover(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// only the rows a request touched: cards, trades, orders, ledger entries and idempotent responses are
+// inserted or replaced by id or key, holdings replace the list of each card holder given and closed positions are appended to it.
+// Callers start trades, closed positions and ledger entries from empty maps and only add what they
+// create, fields left at `..Changes::default()` are not written
+#[derive(Default)]
+pub struct Changes<'a> {
+    pub accounts: Option<&'a HashMap<u64, CardInfo>>,
+    pub trades: Option<&'a HashMap<i64, TradeHistory>>,
+    pub holdings: Option<&'a HashMap<String, Vec<StockHold>>>,
+    pub orders: Option<&'a HashMap<i64, LimitOrder>>,
+    pub closed: Option<&'a HashMap<String, Vec<ClosedPosition>>>,
+    pub ledger: Option<&'a HashMap<i64, LedgerEntry>>,
+    pub responses: Option<&'a HashMap<String, IdempotentResponse>>,
+}
+
+fn upsert<K: Clone + Eq + Hash, V: Clone>(stored: &mut HashMap<K, V>, rows: &HashMap<K, V>) {
+    stored.extend(rows.iter().map(|(key, row)| (key.clone(), row.clone())));
+}
+
+fn append<V: Clone>(stored: &mut HashMap<String, Vec<V>>, rows: &HashMap<String, Vec<V>>) {
+    for (card_holder, new) in rows {
+        stored.entry(card_holder.clone()).or_default().extend(new.iter().cloned());
+    }
+}
+
+pub struct JsonStorage {
+    pub account_path: String,
+    pub trade_path: String,
+    pub stockhold_path: String,
+    pub order_path: String,
+    pub closed_path: String,
+    pub ledger_path: String,
+    pub sequence_path: String,
+    pub idempotency_path: String,
+    pub journal_path: String,
+    ids: Sequences,
+}
+
+impl Default for JsonStorage {
+    fn default() -> Self {
+        JsonStorage {
+            account_path: String::from("account.json"),
+            trade_path: String::from("trade.json"),
+            stockhold_path: String::from("stockhold.json"),
+            order_path: String::from("orders.json"),
+            closed_path: String::from("closed.json"),
+            ledger_path: String::from("ledger.json"),
+            sequence_path: String::from("sequences.json"),
+            idempotency_path: String::from("idempotency.json"),
+            journal_path: String::from("journal.json"),
+            ids: Sequences::default(),
+        }
+    }
+}
+
+impl JsonStorage {
+    // the same file names as the default, kept in `dir` instead of the working directory
+    pub fn in_dir(dir: &Path) -> Self {
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        JsonStorage {
+            account_path: path("account.json"),
+            trade_path: path("trade.json"),
+            stockhold_path: path("stockhold.json"),
+            order_path: path("orders.json"),
+            closed_path: path("closed.json"),
+            ledger_path: path("ledger.json"),
+            sequence_path: path("sequences.json"),
+            idempotency_path: path("idempotency.json"),
+            journal_path: path("journal.json"),
+            ids: Sequences::default(),
+        }
+    }
+
+    fn apply_journal(&self, journal: &HashMap<String, String>) -> Result<(), String> {
+        for (path, content) in journal {
+            write_atomic(path, content.as_bytes())
+                .map_err(|e| format!("Failed to write {} ：{}", path, e))?;
+        }
+        fs::remove_file(&self.journal_path)
+            .map_err(|e| format!("Failed to remove {} ：{}", self.journal_path, e))
+    }
+
+    fn load_responses(&self) -> Result<HashMap<String, IdempotentResponse>, String> {
+        // created by the first request with an idempotency key
+        if !Path::new(&self.idempotency_path).exists() {
+            return Ok(HashMap::new());
+        }
+        get_map(&self.idempotency_path)
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load_accounts(&self) -> Result<HashMap<u64, CardInfo>, String> {
+        get_map(&self.account_path)
+    }
+
+    fn save_accounts(&self, accounts: &HashMap<u64, CardInfo>) -> Result<(), String> {
+        self.save_changes(Changes { accounts: Some(accounts), ..Changes::default() })
+    }
+
+    fn load_trades(&self) -> Result<HashMap<i64, TradeHistory>, String> {
+        get_map(&self.trade_path)
+    }
+
+    fn save_trades(&self, trades: &HashMap<i64, TradeHistory>) -> Result<(), String> {
+        self.save_changes(Changes { trades: Some(trades), ..Changes::default() })
+    }
+
+    fn load_holdings(&self) -> Result<HashMap<String, Vec<StockHold>>, String> {
+        get_map(&self.stockhold_path)
+    }
+
+    fn save_holdings(&self, holdings: &HashMap<String, Vec<StockHold>>) -> Result<(), String> {
+        self.save_changes(Changes { holdings: Some(holdings), ..Changes::default() })
+    }
+
+    fn load_orders(&self) -> Result<HashMap<i64, LimitOrder>, String> {
+        // created by the first order, older installs don't have it
+        if !Path::new(&self.order_path).exists() {
+            return Ok(HashMap::new());
+        }
+        get_map(&self.order_path)
+    }
+
+    fn save_orders(&self, orders: &HashMap<i64, LimitOrder>) -> Result<(), String> {
+        self.save_changes(Changes { orders: Some(orders), ..Changes::default() })
+    }
+
+    fn load_closed(&self) -> Result<HashMap<String, Vec<ClosedPosition>>, String> {
+        if !Path::new(&self.closed_path).exists() {
+            return Ok(HashMap::new());
+        }
+        get_map(&self.closed_path)
+    }
+
+    fn save_closed(&self, closed: &HashMap<String, Vec<ClosedPosition>>) -> Result<(), String> {
+        self.save_changes(Changes { closed: Some(closed), ..Changes::default() })
+    }
+
+    fn load_ledger(&self) -> Result<HashMap<i64, LedgerEntry>, String> {
+        // opening balances are posted on the first start with a ledger
+        if !Path::new(&self.ledger_path).exists() {
+            return Ok(HashMap::new());
+        }
+        get_map(&self.ledger_path)
+    }
+
+    fn save_ledger(&self, ledger: &HashMap<i64, LedgerEntry>) -> Result<(), String> {
+        self.save_changes(Changes { ledger: Some(ledger), ..Changes::default() })
+    }
+
+    fn load_sequence(&self, name: &str) -> Result<i64, String> {
+        if !Path::new(&self.sequence_path).exists() {
+            return Ok(0);
+        }
+        let sequences: HashMap<String, i64> = get_map(&self.sequence_path)?;
+        Ok(sequences.get(name).copied().unwrap_or(0))
+    }
+
+    fn save_sequence(&self, name: &str, value: i64) -> Result<(), String> {
+        let mut sequences: HashMap<String, i64> = if Path::new(&self.sequence_path).exists() {
+            get_map(&self.sequence_path)?
+        } else {
+            HashMap::new()
+        };
+        sequences.insert(name.to_string(), value);
+        write_json_to_file(&self.sequence_path, &sequences)
+            .map_err(|e| format!("Failed to write {} ：{}", self.sequence_path, e))
+    }
+
+    fn load_response(&self, key: &str) -> Result<Option<IdempotentResponse>, String> {
+        Ok(self.load_responses()?.remove(key))
+    }
+
+    fn prune_responses(&self, before: i64) -> Result<usize, String> {
+        let mut responses = self.load_responses()?;
+        let count = responses.len();
+        responses.retain(|_, stored| stored.stored_at >= before);
+        if responses.len() == count {
+            return Ok(0);
+        }
+        write_json_to_file(&self.idempotency_path, &responses)
+            .map_err(|e| format!("Failed to write {} ：{}", self.idempotency_path, e))?;
+        Ok(count - responses.len())
+    }
+
+    fn save_changes(&self, changes: Changes) -> Result<(), String> {
+        // a JSON file can only be rewritten whole, so the changed rows are merged into what is stored;
+        // redo journal: the full new content of every file is made durable first,
+        // so a crash after this point is rolled forward by `recover`
+        let mut journal: HashMap<String, String> = HashMap::new();
+        let to_json = |e: serde_json::Error| e.to_string();
+        if let Some(rows) = changes.accounts {
+            let mut accounts = self.load_accounts()?;
+            upsert(&mut accounts, rows);
+            journal.insert(self.account_path.clone(), serde_json::to_string_pretty(&accounts).map_err(to_json)?);
+        }
+        if let Some(rows) = changes.trades {
+            let mut trades = self.load_trades()?;
+            upsert(&mut trades, rows);
+            journal.insert(self.trade_path.clone(), serde_json::to_string_pretty(&trades).map_err(to_json)?);
+        }
+        if let Some(rows) = changes.holdings {
+            let mut holdings = self.load_holdings()?;
+            upsert(&mut holdings, rows);
+            journal.insert(self.stockhold_path.clone(), serde_json::to_string_pretty(&holdings).map_err(to_json)?);
+        }
+        if let Some(rows) = changes.orders {
+            let mut orders = self.load_orders()?;
+            upsert(&mut orders, rows);
+            journal.insert(self.order_path.clone(), serde_json::to_string_pretty(&orders).map_err(to_json)?);
+        }
+        if let Some(rows) = changes.closed {
+            let mut closed = self.load_closed()?;
+            append(&mut closed, rows);
+            journal.insert(self.closed_path.clone(), serde_json::to_string_pretty(&closed).map_err(to_json)?);
+        }
+        if let Some(rows) = changes.ledger {
+            let mut ledger = self.load_ledger()?;
+            upsert(&mut ledger, rows);
+            journal.insert(self.ledger_path.clone(), serde_json::to_string_pretty(&ledger).map_err(to_json)?);
+        }
+        if let Some(rows) = changes.responses {
+            let mut responses = self.load_responses()?;
+            upsert(&mut responses, rows);
+            journal.insert(self.idempotency_path.clone(), serde_json::to_string_pretty(&responses).map_err(to_json)?);
+        }
+
+        write_json_to_file(&self.journal_path, &journal)
+            .map_err(|e| format!("Failed to write {} ：{}", self.journal_path, e))?;
+        self.apply_journal(&journal)
+    }
+
+    fn recover(&self) -> Result<(), String> {
+        if !Path::new(&self.journal_path).exists() {
+            return Ok(());
+        }
+        match get_map::<HashMap<String, String>>(&self.journal_path) {
+            Ok(journal) => {
+                println!("Replaying unfinished update from {}", self.journal_path);
+                self.apply_journal(&journal)
+            }
+            Err(e) => {
+                // the journal itself never finished writing, so no data file was touched yet
+                eprintln!("Discarding incomplete journal： {}", e);
+                fs::remove_file(&self.journal_path)
+                    .map_err(|e| format!("Failed to remove {} ：{}", self.journal_path, e))
+            }
+        }
+    }
+}
+
+impl IdSource for JsonStorage {
+    fn next_id(&self, sequence: Sequence) -> Result<i64, String> {
+        self.ids.next(self, sequence)
+    }
+}
+
+// keeps everything in process, for tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    accounts: Mutex<HashMap<u64, CardInfo>>,
+    trades: Mutex<HashMap<i64, TradeHistory>>,
+    holdings: Mutex<HashMap<String, Vec<StockHold>>>,
+    orders: Mutex<HashMap<i64, LimitOrder>>,
+    closed: Mutex<HashMap<String, Vec<ClosedPosition>>>,
+    ledger: Mutex<HashMap<i64, LedgerEntry>>,
+    sequences: Mutex<HashMap<String, i64>>,
+    responses: Mutex<HashMap<String, IdempotentResponse>>,
+    ids: Sequences,
+}
+
+impl MemoryStorage {
+    pub fn new(
+        accounts: HashMap<u64, CardInfo>,
+        trades: HashMap<i64, TradeHistory>,
+        holdings: HashMap<String, Vec<StockHold>>,
+    ) -> Self {
+        MemoryStorage {
+            accounts: Mutex::new(accounts),
+            trades: Mutex::new(trades),
+            holdings: Mutex::new(holdings),
+            orders: Mutex::new(HashMap::new()),
+            closed: Mutex::new(HashMap::new()),
+            ledger: Mutex::new(HashMap::new()),
+            sequences: Mutex::new(HashMap::new()),
+            responses: Mutex::new(HashMap::new()),
+            ids: Sequences::default(),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load_accounts(&self) -> Result<HashMap<u64, CardInfo>, String> {
+        let accounts = self.accounts.lock().map_err(|e| e.to_string())?;
+        Ok(accounts.clone())
+    }
+
+    fn save_accounts(&self, accounts: &HashMap<u64, CardInfo>) -> Result<(), String> {
+        self.save_changes(Changes { accounts: Some(accounts), ..Changes::default() })
+    }
+
+    fn load_trades(&self) -> Result<HashMap<i64, TradeHistory>, String> {
+        let trades = self.trades.lock().map_err(|e| e.to_string())?;
+        Ok(trades.clone())
+    }
+
+    fn save_trades(&self, trades: &HashMap<i64, TradeHistory>) -> Result<(), String> {
+        self.save_changes(Changes { trades: Some(trades), ..Changes::default() })
+    }
+
+    fn load_holdings(&self) -> Result<HashMap<String, Vec<StockHold>>, String> {
+        let holdings = self.holdings.lock().map_err(|e| e.to_string())?;
+        Ok(holdings.clone())
+    }
+
+    fn save_holdings(&self, holdings: &HashMap<String, Vec<StockHold>>) -> Result<(), String> {
+        self.save_changes(Changes { holdings: Some(holdings), ..Changes::default() })
+    }
+
+    fn load_orders(&self) -> Result<HashMap<i64, LimitOrder>, String> {
+        let orders = self.orders.lock().map_err(|e| e.to_string())?;
+        Ok(orders.clone())
+    }
+
+    fn save_orders(&self, orders: &HashMap<i64, LimitOrder>) -> Result<(), String> {
+        self.save_changes(Changes { orders: Some(orders), ..Changes::default() })
+    }
+
+    fn load_closed(&self) -> Result<HashMap<String, Vec<ClosedPosition>>, String> {
+        let closed = self.closed.lock().map_err(|e| e.to_string())?;
+        Ok(closed.clone())
+    }
+
+    fn save_closed(&self, closed: &HashMap<String, Vec<ClosedPosition>>) -> Result<(), String> {
+        self.save_changes(Changes { closed: Some(closed), ..Changes::default() })
+    }
+
+    fn load_ledger(&self) -> Result<HashMap<i64, LedgerEntry>, String> {
+        let ledger = self.ledger.lock().map_err(|e| e.to_string())?;
+        Ok(ledger.clone())
+    }
+
+    fn save_ledger(&self, ledger: &HashMap<i64, LedgerEntry>) -> Result<(), String> {
+        self.save_changes(Changes { ledger: Some(ledger), ..Changes::default() })
+    }
+
+    fn load_sequence(&self, name: &str) -> Result<i64, String> {
+        let sequences = self.sequences.lock().map_err(|e| e.to_string())?;
+        Ok(sequences.get(name).copied().unwrap_or(0))
+    }
+
+    fn save_sequence(&self, name: &str, value: i64) -> Result<(), String> {
+        self.sequences.lock().map_err(|e| e.to_string())?.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn load_response(&self, key: &str) -> Result<Option<IdempotentResponse>, String> {
+        let responses = self.responses.lock().map_err(|e| e.to_string())?;
+        Ok(responses.get(key).cloned())
+    }
+
+    fn prune_responses(&self, before: i64) -> Result<usize, String> {
+        let mut responses = self.responses.lock().map_err(|e| e.to_string())?;
+        let count = responses.len();
+        responses.retain(|_, stored| stored.stored_at >= before);
+        Ok(count - responses.len())
+    }
+
+    fn save_changes(&self, changes: Changes) -> Result<(), String> {
+        let mut accounts = self.accounts.lock().map_err(|e| e.to_string())?;
+        let mut trades = self.trades.lock().map_err(|e| e.to_string())?;
+        let mut holdings = self.holdings.lock().map_err(|e| e.to_string())?;
+        let mut orders = self.orders.lock().map_err(|e| e.to_string())?;
+        let mut closed = self.closed.lock().map_err(|e| e.to_string())?;
+        let mut ledger = self.ledger.lock().map_err(|e| e.to_string())?;
+        let mut responses = self.responses.lock().map_err(|e| e.to_string())?;
+        if let Some(rows) = changes.accounts {
+            upsert(&mut accounts, rows);
+        }
+        if let Some(rows) = changes.trades {
+            upsert(&mut trades, rows);
+        }
+        if let Some(rows) = changes.holdings {
+            upsert(&mut holdings, rows);
+        }
+        if let Some(rows) = changes.orders {
+            upsert(&mut orders, rows);
+        }
+        if let Some(rows) = changes.closed {
+            append(&mut closed, rows);
+        }
+        if let Some(rows) = changes.ledger {
+            upsert(&mut ledger, rows);
+        }
+        if let Some(rows) = changes.responses {
+            upsert(&mut responses, rows);
+        }
+        Ok(())
+    }
+}
+
+impl IdSource for MemoryStorage {
+    fn next_id(&self, sequence: Sequence) -> Result<i64, String> {
+        self.ids.next(self, sequence)
+    }
+}
+
+pub enum StorageConfig {
+    Json,
+    Sqlite { path: String },
+}
+
+impl StorageConfig {
+    // STORAGE_BACKEND=json (default) or sqlite, SQLITE_PATH defaults to bank.db
+    pub fn from_env() -> Result<Self, String> {
+        let backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| String::from("json"));
+        match backend.to_lowercase().as_str() {
+            "json" => Ok(StorageConfig::Json),
+            "sqlite" => Ok(StorageConfig::Sqlite {
+                path: env::var("SQLITE_PATH").unwrap_or_else(|_| String::from("bank.db")),
+            }),
+            other => Err(format!("Unknown storage backend：{}", other)),
+        }
+    }
+
+    pub fn open(&self) -> Result<SharedStorage, String> {
+        let ids = Sequences::new(IdFormat::from_env()?);
+        let storage: SharedStorage = match self {
+            StorageConfig::Json => Arc::new(JsonStorage { ids, ..JsonStorage::default() }),
+            StorageConfig::Sqlite { path } => Arc::new(SqliteStorage::open(path)?.with_ids(ids)),
+        };
+        storage.recover()?;
+        Ok(storage)
+    }
+}
+
+// one-shot copy of every account, trade, holding, order, closed position, ledger entry and id sequence, e.g. account.json -> sqlite
+pub fn import_storage(from: &dyn Storage, to: &dyn Storage) -> Result<(usize, usize, usize, usize, usize, usize), String> {
+    // rows are merged into the target, importing twice would append every closed position again
+    if !to.load_accounts()?.is_empty() {
+        return Err(String::from("The target storage already has cards, import into an empty one"));
+    }
+    let accounts = from.load_accounts()?;
+    let trades = from.load_trades()?;
+    let holdings = from.load_holdings()?;
+    let orders = from.load_orders()?;
+    let closed = from.load_closed()?;
+    let ledger = from.load_ledger()?;
+
+    to.save_changes(Changes {
+        accounts: Some(&accounts),
+        trades: Some(&trades),
+        holdings: Some(&holdings),
+        orders: Some(&orders),
+        closed: Some(&closed),
+        ledger: Some(&ledger),
+        ..Changes::default()
+    })?;
+    for sequence in Sequence::ALL {
+        to.save_sequence(sequence.name(), from.load_sequence(sequence.name())?)?;
+    }
+
+    Ok((accounts.len(), trades.len(), holdings.values().map(Vec::len).sum(), orders.len(), closed.values().map(Vec::len).sum(), ledger.len()))
+}
+
+// run on startup: cards saved before the ledger existed get an opening entry, and any card whose
+// stored balance disagrees with its ledger account is reported
+pub fn open_ledger(storage: &dyn Storage, now: i64) -> Result<usize, String> {
+    let card_map = storage.load_accounts()?;
+    let mut ledger = storage.load_ledger()?;
+    let mut opening = HashMap::new();
+    let opened = open_balances(&card_map, &account_balances(&ledger), &mut opening, storage, now)?;
+    if opened > 0 {
+        storage.save_changes(Changes { ledger: Some(&opening), ..Changes::default() })?;
+        ledger.extend(opening);
+    }
+
+    for (card_holder, stored, derived) in check_balances(&card_map, &ledger) {
+        eprintln!("Balance of {} is {} but the ledger says {}", card_holder, stored, derived);
+    }
+    Ok(opened)
+}
+
+// reconciliation report over everything currently stored, read only
+pub fn reconcile_storage(storage: &dyn Storage, now: i64) -> Result<ReconciliationReport, String> {
+    let card_map = storage.load_accounts()?;
+    let trade_map = storage.load_trades()?;
+    let holdings = storage.load_holdings()?;
+    let ledger = storage.load_ledger()?;
+    Ok(reconcile(&card_map, &trade_map, &holdings, &ledger, now))
+}
+
+pub type SharedLocks = Arc<AccountLocks>;
+
+#[derive(Default)]
+pub struct AccountLocks {
+    accounts: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    store: tokio::sync::Mutex<()>,
+}
+
+impl AccountLocks {
+    // held for a whole request so two commands from the same card run one after another
+    pub async fn account(&self, card_holder: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+            accounts.retain(|_, lock| Arc::strong_count(lock) > 1);
+            accounts.entry(card_holder.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    // held from load to save, every storage write reads the latest committed maps
+    pub async fn store(&self) -> MutexGuard<'_, ()> {
+        self.store.lock().await
+    }
+}
@@ -0,0 +1,777 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, ToSql, Transaction};
+use rust_decimal::Decimal;
+use structure::{CardInfo, ClosedPosition, IdempotentResponse, LedgerEntry, LimitOrder, OrderStatus, Posting, Side, TargetInfo, TradeHistory, TradeIndex, TradeRef, TransactionType, Stock, StockHold};
+use function::{IdSource, Sequence};
+use crate::{Changes, Sequences, Storage};
+
+// every entry is applied once, in order, and tracked by PRAGMA user_version
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE cards (
+        id INTEGER PRIMARY KEY,
+        card_holder TEXT NOT NULL,
+        card_number TEXT NOT NULL,
+        good_thru TEXT NOT NULL,
+        verify_number TEXT NOT NULL,
+        scheme TEXT NOT NULL,
+        card_type TEXT NOT NULL,
+        balance TEXT NOT NULL
+    );
+    CREATE TABLE connections (
+        card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+        platform TEXT NOT NULL,
+        target TEXT NOT NULL,
+        token TEXT NOT NULL
+    );
+    CREATE TABLE card_trades (
+        card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        trade_id INTEGER NOT NULL,
+        PRIMARY KEY (card_id, timestamp)
+    );
+    CREATE TABLE trades (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        amount REAL NOT NULL,
+        target_user TEXT NOT NULL
+    );
+    CREATE TABLE stock_holds (
+        card_holder TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        buy_type TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        hand TEXT NOT NULL,
+        leverage TEXT NOT NULL,
+        price TEXT NOT NULL,
+        PRIMARY KEY (card_holder, position)
+    );
+    CREATE INDEX idx_cards_holder ON cards(card_holder);",
+    "ALTER TABLE trades ADD COLUMN linked_trade INTEGER;",
+    "CREATE TABLE orders (
+        id INTEGER PRIMARY KEY,
+        card_holder TEXT NOT NULL,
+        side TEXT NOT NULL,
+        buy_type TEXT NOT NULL,
+        symbol TEXT NOT NULL,
+        hand TEXT NOT NULL,
+        leverage TEXT NOT NULL,
+        limit_price TEXT NOT NULL,
+        reserved TEXT NOT NULL,
+        hold_timestamp INTEGER,
+        created_at INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        filled_price TEXT,
+        closed_at INTEGER
+    );
+    CREATE INDEX idx_orders_status ON orders(status);",
+    "ALTER TABLE stock_holds ADD COLUMN stop_loss TEXT;
+    ALTER TABLE stock_holds ADD COLUMN take_profit TEXT;
+    ALTER TABLE trades ADD COLUMN reason TEXT;",
+    "CREATE TABLE closed_positions (
+        card_holder TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        symbol TEXT NOT NULL,
+        buy_type TEXT NOT NULL,
+        hand TEXT NOT NULL,
+        leverage TEXT NOT NULL,
+        entry_price TEXT NOT NULL,
+        exit_price TEXT NOT NULL,
+        opened_at INTEGER NOT NULL,
+        closed_at INTEGER NOT NULL,
+        earning TEXT NOT NULL,
+        reason TEXT,
+        trade_id INTEGER NOT NULL,
+        PRIMARY KEY (card_holder, position)
+    );",
+    "ALTER TABLE cards ADD COLUMN leaderboard_opt_out INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE ledger_entries (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        trade_id INTEGER,
+        memo TEXT NOT NULL
+    );
+    CREATE TABLE ledger_postings (
+        entry_id INTEGER NOT NULL REFERENCES ledger_entries(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        account TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        PRIMARY KEY (entry_id, position)
+    );
+    CREATE INDEX idx_ledger_postings_account ON ledger_postings(account);",
+    "CREATE TABLE card_trades_seq (
+        card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+        seq INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        trade_id INTEGER NOT NULL,
+        PRIMARY KEY (card_id, seq)
+    );
+    INSERT INTO card_trades_seq (card_id, seq, timestamp, trade_id)
+        SELECT card_id, ROW_NUMBER() OVER (PARTITION BY card_id ORDER BY timestamp, trade_id), timestamp, trade_id
+        FROM card_trades;
+    DROP TABLE card_trades;
+    ALTER TABLE card_trades_seq RENAME TO card_trades;",
+    "CREATE TABLE sequences (
+        name TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );",
+    "CREATE INDEX idx_cards_number ON cards(card_number);
+    CREATE INDEX idx_connections_card ON connections(card_id);
+    CREATE INDEX idx_orders_holder ON orders(card_holder);",
+    "CREATE TABLE idempotent_responses (
+        key TEXT PRIMARY KEY,
+        status INTEGER NOT NULL,
+        content_type TEXT,
+        body TEXT NOT NULL,
+        stored_at INTEGER NOT NULL
+    );",
+];
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    ids: Sequences,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Failed to open {} ：{}", path, e))?;
+        Self::with_connection(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self, String> {
+        let conn = Connection::open_in_memory().map_err(|e| e.to_string())?;
+        Self::with_connection(conn)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<Self, String> {
+        conn.pragma_update(None, "foreign_keys", true).map_err(|e| e.to_string())?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage { conn: Mutex::new(conn), ids: Sequences::default() })
+    }
+
+    pub fn with_ids(self, ids: Sequences) -> Self {
+        SqliteStorage { ids, ..self }
+    }
+
+    pub fn schema_version(&self) -> Result<usize, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        current_version(&conn)
+    }
+
+    fn write<F>(&self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&Transaction) -> rusqlite::Result<()>,
+    {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        f(&tx).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+}
+
+fn current_version(conn: &Connection) -> Result<usize, String> {
+    conn.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))
+        .map(|v| v as usize)
+        .map_err(|e| e.to_string())
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version = current_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(format!("Database schema version {} is newer than this build ({})", version, MIGRATIONS.len()));
+    }
+
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(sql)
+            .map_err(|e| format!("Failed to apply migration {} ：{}", i + 1, e))?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn parse_decimal(value: String) -> rusqlite::Result<Decimal> {
+    Decimal::from_str(&value)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+// OrderSide and OrderStatus are stored by name
+fn parse_enum<T: FromStr<Err = String>>(value: String) -> rusqlite::Result<T> {
+    T::from_str(&value)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into()))
+}
+
+// buy types written before they were typed may be lowercase, see `Side::from_stored`
+fn parse_side(value: String) -> rusqlite::Result<Side> {
+    Side::from_stored(&value)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into()))
+}
+
+// each card is inserted or updated, its connections are replaced and index entries past the last stored seq are added
+fn write_accounts(tx: &Transaction, accounts: &HashMap<u64, CardInfo>) -> rusqlite::Result<()> {
+    let mut card_stmt = tx.prepare(
+        "INSERT INTO cards (id, card_holder, card_number, good_thru, verify_number, scheme, card_type, balance, leaderboard_opt_out)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT(id) DO UPDATE SET
+             card_holder = excluded.card_holder, card_number = excluded.card_number, good_thru = excluded.good_thru,
+             verify_number = excluded.verify_number, scheme = excluded.scheme, card_type = excluded.card_type,
+             balance = excluded.balance, leaderboard_opt_out = excluded.leaderboard_opt_out"
+    )?;
+    let mut clear_stmt = tx.prepare("DELETE FROM connections WHERE card_id = ?1")?;
+    let mut conn_stmt = tx.prepare(
+        "INSERT INTO connections (card_id, platform, target, token) VALUES (?1, ?2, ?3, ?4)"
+    )?;
+    let mut last_stmt = tx.prepare("SELECT COALESCE(MAX(seq), 0) FROM card_trades WHERE card_id = ?1")?;
+    let mut trade_stmt = tx.prepare(
+        "INSERT INTO card_trades (card_id, seq, timestamp, trade_id) VALUES (?1, ?2, ?3, ?4)"
+    )?;
+
+    for (id, card) in accounts {
+        let id = *id as i64;
+        card_stmt.execute(params![
+            id,
+            card.card_holder,
+            card.card_number,
+            card.good_thru,
+            card.verify_number,
+            card.scheme,
+            card.card_type,
+            card.balance.to_string(),
+            card.leaderboard_opt_out,
+        ])?;
+
+        clear_stmt.execute(params![id])?;
+        if let Some(connection) = &card.connection {
+            for (platform, targets) in connection {
+                for info in targets {
+                    conn_stmt.execute(params![id, platform, info.target, info.token])?;
+                }
+            }
+        }
+
+        // the index only ever grows, so everything up to the stored seq is already there
+        let last: i64 = last_stmt.query_row(params![id], |row| row.get(0))?;
+        if let Some(transaction) = &card.transaction {
+            for trade in transaction.iter().filter(|trade| trade.seq as i64 > last) {
+                trade_stmt.execute(params![id, trade.seq as i64, trade.timestamp, trade.trade_id])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_trades(tx: &Transaction, trades: &HashMap<i64, TradeHistory>) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare(
+        "INSERT OR REPLACE INTO trades (id, timestamp, action, amount, target_user, linked_trade, reason) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+    )?;
+    for (id, trade) in trades {
+        let (action, amount) = match trade.transaction_type {
+            TransactionType::Credit { amount } => ("credit", amount),
+            TransactionType::Debit { amount } => ("debit", amount),
+        };
+        stmt.execute(params![id, trade.timestamp, action, amount, trade.target_user, trade.linked_trade, trade.reason])?;
+    }
+    Ok(())
+}
+
+// replaces the holdings of every card holder in `holdings`
+fn write_holdings(tx: &Transaction, holdings: &HashMap<String, Vec<StockHold>>) -> rusqlite::Result<()> {
+    let mut clear_stmt = tx.prepare("DELETE FROM stock_holds WHERE card_holder = ?1")?;
+    let mut stmt = tx.prepare(
+        "INSERT INTO stock_holds (card_holder, position, timestamp, buy_type, symbol, hand, leverage, price, stop_loss, take_profit)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
+    )?;
+    for (card_holder, holds) in holdings {
+        clear_stmt.execute(params![card_holder])?;
+        for (position, hold) in holds.iter().enumerate() {
+            stmt.execute(params![
+                card_holder,
+                position as i64,
+                hold.timestamp,
+                hold.stock.buy_type.to_string(),
+                hold.stock.symbol,
+                hold.stock.hand.to_string(),
+                hold.stock.leverage.to_string(),
+                hold.stock.price.to_string(),
+                hold.stop_loss.map(|p| p.to_string()),
+                hold.take_profit.map(|p| p.to_string()),
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_orders(tx: &Transaction, orders: &HashMap<i64, LimitOrder>) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare(
+        "INSERT OR REPLACE INTO orders (id, card_holder, side, buy_type, symbol, hand, leverage, limit_price, reserved,
+                                        hold_timestamp, created_at, status, filled_price, closed_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)"
+    )?;
+    for (id, order) in orders {
+        stmt.execute(params![
+            id,
+            order.card_holder,
+            order.side.to_string(),
+            order.buy_type.to_string(),
+            order.symbol,
+            order.hand.to_string(),
+            order.leverage.to_string(),
+            order.limit_price.to_string(),
+            order.reserved.to_string(),
+            order.hold_timestamp,
+            order.created_at,
+            order.status.to_string(),
+            order.filled_price.map(|p| p.to_string()),
+            order.closed_at,
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_ledger(tx: &Transaction, ledger: &HashMap<i64, LedgerEntry>) -> rusqlite::Result<()> {
+    let mut entry_stmt = tx.prepare(
+        "INSERT INTO ledger_entries (id, timestamp, trade_id, memo) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(id) DO UPDATE SET timestamp = excluded.timestamp, trade_id = excluded.trade_id, memo = excluded.memo"
+    )?;
+    let mut clear_stmt = tx.prepare("DELETE FROM ledger_postings WHERE entry_id = ?1")?;
+    let mut posting_stmt = tx.prepare(
+        "INSERT INTO ledger_postings (entry_id, position, account, amount) VALUES (?1, ?2, ?3, ?4)"
+    )?;
+    for (id, entry) in ledger {
+        entry_stmt.execute(params![id, entry.timestamp, entry.trade_id, entry.memo])?;
+        clear_stmt.execute(params![id])?;
+        for (position, posting) in entry.postings.iter().enumerate() {
+            posting_stmt.execute(params![id, position as i64, posting.account, posting.amount.to_string()])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_responses(tx: &Transaction, responses: &HashMap<String, IdempotentResponse>) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare(
+        "INSERT OR REPLACE INTO idempotent_responses (key, status, content_type, body, stored_at) VALUES (?1, ?2, ?3, ?4, ?5)"
+    )?;
+    for (key, stored) in responses {
+        stmt.execute(params![key, stored.status, stored.content_type, stored.body, stored.stored_at])?;
+    }
+    Ok(())
+}
+
+// appends to the closed positions of every card holder in `closed`
+fn write_closed(tx: &Transaction, closed: &HashMap<String, Vec<ClosedPosition>>) -> rusqlite::Result<()> {
+    let mut next_stmt = tx.prepare("SELECT COALESCE(MAX(position) + 1, 0) FROM closed_positions WHERE card_holder = ?1")?;
+    let mut stmt = tx.prepare(
+        "INSERT INTO closed_positions (card_holder, position, symbol, buy_type, hand, leverage, entry_price, exit_price,
+                                       opened_at, closed_at, earning, reason, trade_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)"
+    )?;
+    for (card_holder, positions) in closed {
+        let next: i64 = next_stmt.query_row(params![card_holder], |row| row.get(0))?;
+        for (position, closed) in positions.iter().enumerate() {
+            stmt.execute(params![
+                card_holder,
+                next + position as i64,
+                closed.symbol,
+                closed.buy_type.to_string(),
+                closed.hand.to_string(),
+                closed.leverage.to_string(),
+                closed.entry_price.to_string(),
+                closed.exit_price.to_string(),
+                closed.opened_at,
+                closed.closed_at,
+                closed.earning.to_string(),
+                closed.reason,
+                closed.trade_id,
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+// "?1, ?2, ..." for an IN list of `count` values
+fn placeholders(count: usize) -> String {
+    (1..=count).map(|i| format!("?{}", i)).collect::<Vec<_>>().join(", ")
+}
+
+// the cards matching `filter`, a condition on the cards table, with their connections and trade indexes
+fn read_cards(conn: &Connection, filter: &str, values: &[&dyn ToSql]) -> rusqlite::Result<HashMap<u64, CardInfo>> {
+    let mut cards: HashMap<u64, CardInfo> = HashMap::new();
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, card_holder, card_number, good_thru, verify_number, scheme, card_type, balance, leaderboard_opt_out FROM cards WHERE {}",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        Ok((row.get::<_, i64>(0)? as u64, CardInfo {
+            card_holder: row.get(1)?,
+            card_number: row.get(2)?,
+            good_thru: row.get(3)?,
+            verify_number: row.get(4)?,
+            scheme: row.get(5)?,
+            card_type: row.get(6)?,
+            balance: parse_decimal(row.get(7)?)?,
+            connection: None,
+            transaction: None,
+            leaderboard_opt_out: row.get(8)?,
+        }))
+    })?;
+    for row in rows {
+        let (id, card) = row?;
+        cards.insert(id, card);
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT card_id, platform, target, token FROM connections WHERE card_id IN (SELECT id FROM cards WHERE {}) ORDER BY rowid",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        Ok((row.get::<_, i64>(0)? as u64, row.get::<_, String>(1)?, TargetInfo {
+            target: row.get(2)?,
+            token: row.get(3)?,
+        }))
+    })?;
+    for row in rows {
+        let (id, platform, info) = row?;
+        if let Some(card) = cards.get_mut(&id) {
+            card.connection.get_or_insert_with(HashMap::new)
+                .entry(platform)
+                .or_default()
+                .push(info);
+        }
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT card_id, seq, timestamp, trade_id FROM card_trades WHERE card_id IN (SELECT id FROM cards WHERE {}) ORDER BY card_id, seq",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        Ok((row.get::<_, i64>(0)? as u64, TradeRef {
+            seq: row.get::<_, i64>(1)? as u64,
+            timestamp: row.get(2)?,
+            trade_id: row.get(3)?,
+        }))
+    })?;
+    let mut indexes: HashMap<u64, Vec<TradeRef>> = HashMap::new();
+    for row in rows {
+        let (id, trade) = row?;
+        indexes.entry(id).or_default().push(trade);
+    }
+    for (id, trades) in indexes {
+        if let Some(card) = cards.get_mut(&id) {
+            card.transaction = Some(TradeIndex::from(trades));
+        }
+    }
+
+    Ok(cards)
+}
+
+fn read_trades(conn: &Connection, filter: &str, values: &[&dyn ToSql]) -> rusqlite::Result<HashMap<i64, TradeHistory>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, timestamp, action, amount, target_user, linked_trade, reason FROM trades WHERE {}",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        let action: String = row.get(2)?;
+        let amount: f64 = row.get(3)?;
+        let transaction_type = match action.as_str() {
+            "credit" => TransactionType::Credit { amount },
+            _ => TransactionType::Debit { amount },
+        };
+        Ok((row.get::<_, i64>(0)?, TradeHistory {
+            timestamp: row.get(1)?,
+            transaction_type,
+            target_user: row.get(4)?,
+            linked_trade: row.get(5)?,
+            reason: row.get(6)?,
+        }))
+    })?;
+    rows.collect()
+}
+
+fn read_holdings(conn: &Connection, filter: &str, values: &[&dyn ToSql]) -> rusqlite::Result<HashMap<String, Vec<StockHold>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT card_holder, timestamp, buy_type, symbol, hand, leverage, price, stop_loss, take_profit
+         FROM stock_holds WHERE {} ORDER BY card_holder, position",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        Ok((row.get::<_, String>(0)?, StockHold {
+            timestamp: row.get(1)?,
+            stock: Stock {
+                buy_type: parse_side(row.get(2)?)?,
+                symbol: row.get(3)?,
+                hand: parse_decimal(row.get(4)?)?,
+                leverage: parse_decimal(row.get(5)?)?,
+                price: parse_decimal(row.get(6)?)?,
+            },
+            stop_loss: row.get::<_, Option<String>>(7)?.map(parse_decimal).transpose()?,
+            take_profit: row.get::<_, Option<String>>(8)?.map(parse_decimal).transpose()?,
+        }))
+    })?;
+
+    let mut holdings: HashMap<String, Vec<StockHold>> = HashMap::new();
+    for row in rows {
+        let (card_holder, hold) = row?;
+        holdings.entry(card_holder).or_default().push(hold);
+    }
+    Ok(holdings)
+}
+
+fn read_orders(conn: &Connection, filter: &str, values: &[&dyn ToSql]) -> rusqlite::Result<HashMap<i64, LimitOrder>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, card_holder, side, buy_type, symbol, hand, leverage, limit_price, reserved,
+                hold_timestamp, created_at, status, filled_price, closed_at
+         FROM orders WHERE {}",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        Ok((row.get::<_, i64>(0)?, LimitOrder {
+            card_holder: row.get(1)?,
+            side: parse_enum(row.get(2)?)?,
+            buy_type: parse_side(row.get(3)?)?,
+            symbol: row.get(4)?,
+            hand: parse_decimal(row.get(5)?)?,
+            leverage: parse_decimal(row.get(6)?)?,
+            limit_price: parse_decimal(row.get(7)?)?,
+            reserved: parse_decimal(row.get(8)?)?,
+            hold_timestamp: row.get(9)?,
+            created_at: row.get(10)?,
+            status: parse_enum(row.get(11)?)?,
+            filled_price: row.get::<_, Option<String>>(12)?.map(parse_decimal).transpose()?,
+            closed_at: row.get(13)?,
+        }))
+    })?;
+    rows.collect()
+}
+
+fn read_closed(conn: &Connection, filter: &str, values: &[&dyn ToSql]) -> rusqlite::Result<HashMap<String, Vec<ClosedPosition>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT card_holder, symbol, buy_type, hand, leverage, entry_price, exit_price, opened_at, closed_at, earning, reason, trade_id
+         FROM closed_positions WHERE {} ORDER BY card_holder, position",
+        filter
+    ))?;
+    let rows = stmt.query_map(values, |row| {
+        Ok((row.get::<_, String>(0)?, ClosedPosition {
+            symbol: row.get(1)?,
+            buy_type: parse_side(row.get(2)?)?,
+            hand: parse_decimal(row.get(3)?)?,
+            leverage: parse_decimal(row.get(4)?)?,
+            entry_price: parse_decimal(row.get(5)?)?,
+            exit_price: parse_decimal(row.get(6)?)?,
+            opened_at: row.get(7)?,
+            closed_at: row.get(8)?,
+            earning: parse_decimal(row.get(9)?)?,
+            reason: row.get(10)?,
+            trade_id: row.get(11)?,
+        }))
+    })?;
+
+    let mut closed: HashMap<String, Vec<ClosedPosition>> = HashMap::new();
+    for row in rows {
+        let (card_holder, position) = row?;
+        closed.entry(card_holder).or_default().push(position);
+    }
+    Ok(closed)
+}
+
+impl Storage for SqliteStorage {
+    fn load_accounts(&self) -> Result<HashMap<u64, CardInfo>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_cards(&conn, "1", &[]).map_err(|e| e.to_string())
+    }
+
+    fn save_accounts(&self, accounts: &HashMap<u64, CardInfo>) -> Result<(), String> {
+        self.write(|tx| write_accounts(tx, accounts))
+    }
+
+    fn load_trades(&self) -> Result<HashMap<i64, TradeHistory>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_trades(&conn, "1", &[]).map_err(|e| e.to_string())
+    }
+
+    fn save_trades(&self, trades: &HashMap<i64, TradeHistory>) -> Result<(), String> {
+        self.write(|tx| write_trades(tx, trades))
+    }
+
+    fn load_holdings(&self) -> Result<HashMap<String, Vec<StockHold>>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_holdings(&conn, "1", &[]).map_err(|e| e.to_string())
+    }
+
+    fn save_holdings(&self, holdings: &HashMap<String, Vec<StockHold>>) -> Result<(), String> {
+        self.write(|tx| write_holdings(tx, holdings))
+    }
+
+    fn load_orders(&self) -> Result<HashMap<i64, LimitOrder>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_orders(&conn, "1", &[]).map_err(|e| e.to_string())
+    }
+
+    fn save_orders(&self, orders: &HashMap<i64, LimitOrder>) -> Result<(), String> {
+        self.write(|tx| write_orders(tx, orders))
+    }
+
+    fn load_closed(&self) -> Result<HashMap<String, Vec<ClosedPosition>>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_closed(&conn, "1", &[]).map_err(|e| e.to_string())
+    }
+
+    fn save_closed(&self, closed: &HashMap<String, Vec<ClosedPosition>>) -> Result<(), String> {
+        self.write(|tx| write_closed(tx, closed))
+    }
+
+    fn load_ledger(&self) -> Result<HashMap<i64, LedgerEntry>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare("SELECT id, timestamp, trade_id, memo FROM ledger_entries")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, LedgerEntry {
+                timestamp: row.get(1)?,
+                trade_id: row.get(2)?,
+                memo: row.get(3)?,
+                postings: Vec::new(),
+            }))
+        }).map_err(|e| e.to_string())?;
+        let mut ledger = rows.collect::<rusqlite::Result<HashMap<_, _>>>().map_err(|e| e.to_string())?;
+
+        let mut stmt = conn.prepare("SELECT entry_id, account, amount FROM ledger_postings ORDER BY entry_id, position")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, Posting {
+                account: row.get(1)?,
+                amount: parse_decimal(row.get(2)?)?,
+            }))
+        }).map_err(|e| e.to_string())?;
+        for row in rows {
+            let (entry_id, posting) = row.map_err(|e| e.to_string())?;
+            if let Some(entry) = ledger.get_mut(&entry_id) {
+                entry.postings.push(posting);
+            }
+        }
+        Ok(ledger)
+    }
+
+    fn save_ledger(&self, ledger: &HashMap<i64, LedgerEntry>) -> Result<(), String> {
+        self.write(|tx| write_ledger(tx, ledger))
+    }
+
+    fn load_accounts_of(&self, keys: &[&str]) -> Result<HashMap<u64, CardInfo>, String> {
+        if keys.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let list = placeholders(keys.len());
+        let values: Vec<&dyn ToSql> = keys.iter().map(|key| key as &dyn ToSql).collect();
+        read_cards(&conn, &format!("card_holder IN ({list}) OR card_number IN ({list})"), &values)
+            .map_err(|e| e.to_string())
+    }
+
+    fn load_trades_of(&self, ids: &[i64]) -> Result<HashMap<i64, TradeHistory>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut trades = HashMap::new();
+        // stays well below SQLite's limit on bound values
+        for chunk in ids.chunks(500) {
+            let values: Vec<&dyn ToSql> = chunk.iter().map(|id| id as &dyn ToSql).collect();
+            let found = read_trades(&conn, &format!("id IN ({})", placeholders(chunk.len())), &values)
+                .map_err(|e| e.to_string())?;
+            trades.extend(found);
+        }
+        Ok(trades)
+    }
+
+    fn load_holdings_of(&self, card_holder: &str) -> Result<HashMap<String, Vec<StockHold>>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_holdings(&conn, "card_holder = ?1", &[&card_holder]).map_err(|e| e.to_string())
+    }
+
+    fn load_orders_of(&self, card_holder: &str) -> Result<HashMap<i64, LimitOrder>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_orders(&conn, "card_holder = ?1", &[&card_holder]).map_err(|e| e.to_string())
+    }
+
+    fn load_open_orders(&self) -> Result<HashMap<i64, LimitOrder>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_orders(&conn, "status = ?1", &[&OrderStatus::Open.to_string()]).map_err(|e| e.to_string())
+    }
+
+    fn load_closed_of(&self, card_holder: &str) -> Result<HashMap<String, Vec<ClosedPosition>>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        read_closed(&conn, "card_holder = ?1", &[&card_holder]).map_err(|e| e.to_string())
+    }
+
+    fn load_sequence(&self, name: &str) -> Result<i64, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let value = conn.query_row("SELECT value FROM sequences WHERE name = ?1", params![name], |row| row.get(0))
+            .optional()
+            .map_err(|e| e.to_string())?;
+        Ok(value.unwrap_or(0))
+    }
+
+    fn save_sequence(&self, name: &str, value: i64) -> Result<(), String> {
+        self.write(|tx| {
+            tx.execute(
+                "INSERT INTO sequences (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET value = excluded.value",
+                params![name, value],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn load_response(&self, key: &str) -> Result<Option<IdempotentResponse>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.query_row(
+            "SELECT status, content_type, body, stored_at FROM idempotent_responses WHERE key = ?1",
+            params![key],
+            |row| Ok(IdempotentResponse {
+                status: row.get(0)?,
+                content_type: row.get(1)?,
+                body: row.get(2)?,
+                stored_at: row.get(3)?,
+            }),
+        ).optional().map_err(|e| e.to_string())
+    }
+
+    fn prune_responses(&self, before: i64) -> Result<usize, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM idempotent_responses WHERE stored_at < ?1", params![before])
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_changes(&self, changes: Changes) -> Result<(), String> {
+        self.write(|tx| {
+            if let Some(accounts) = changes.accounts {
+                write_accounts(tx, accounts)?;
+            }
+            if let Some(trades) = changes.trades {
+                write_trades(tx, trades)?;
+            }
+            if let Some(holdings) = changes.holdings {
+                write_holdings(tx, holdings)?;
+            }
+            if let Some(orders) = changes.orders {
+                write_orders(tx, orders)?;
+            }
+            if let Some(closed) = changes.closed {
+                write_closed(tx, closed)?;
+            }
+            if let Some(ledger) = changes.ledger {
+                write_ledger(tx, ledger)?;
+            }
+            if let Some(responses) = changes.responses {
+                write_responses(tx, responses)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl IdSource for SqliteStorage {
+    fn next_id(&self, sequence: Sequence) -> Result<i64, String> {
+        self.ids.next(self, sequence)
+    }
+}
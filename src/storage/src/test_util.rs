@@ -0,0 +1,43 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use axum::body::to_bytes;
+use axum::response::Response;
+use rust_decimal::Decimal;
+use serde_json::Value;
+use structure::CardInfo;
+use function::{gen_card, KeyRing};
+use crate::{MemoryStorage, SharedStorage};
+
+// a Classic Visa card holding `balance`, `seed` keeps the card numbers of several cards apart
+pub fn card(card_holder: &str, seed: u64, balance: i64) -> CardInfo {
+    let mut card = gen_card(String::from("Visa"), String::from("Classic"), seed, card_holder).unwrap();
+    card.balance = Decimal::from(balance);
+    card
+}
+
+// one card per (card holder, balance), the nth card gets id and seed n starting at 1
+pub fn storage_with(cards: &[(&str, i64)]) -> MemoryStorage {
+    let accounts = cards.iter()
+        .zip(1..)
+        .map(|((card_holder, balance), id)| (id, card(card_holder, id, *balance)))
+        .collect();
+    MemoryStorage::new(accounts, HashMap::new(), HashMap::new())
+}
+
+pub fn shared_storage(cards: &[(&str, i64)]) -> SharedStorage {
+    Arc::new(storage_with(cards))
+}
+
+// signs with key "k1", whose secret is "secret"
+pub fn key_ring(max_age: u64) -> KeyRing {
+    KeyRing::new(HashMap::from([(String::from("k1"), String::from("secret"))]), String::from("k1"), HashSet::new(), max_age).unwrap()
+}
+
+pub async fn body_text(response: Response) -> String {
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+pub async fn json_body(response: Response) -> Value {
+    serde_json::from_str(&body_text(response).await).unwrap()
+}
@@ -0,0 +1,20 @@
+// shared by the storage integration tests, each of them only uses part of it
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use serde::Serialize;
+use serde_json::Value;
+
+// a fresh directory under the system temp dir, removed again by the test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// stored rows don't implement PartialEq, their JSON form is compared instead
+pub fn json<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap()
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use rust_decimal::Decimal;
+use function::gen_card;
+use storage::{JsonStorage, Storage};
+use common::{json, temp_dir};
+
+#[test]
+fn recover_rolls_a_journal_forward_onto_stale_files() {
+    let dir = temp_dir("journal-replay");
+    let storage = JsonStorage::in_dir(&dir);
+    // a new install starts from empty card and trade files
+    fs::write(&storage.account_path, "{}").unwrap();
+    fs::write(&storage.trade_path, "{}").unwrap();
+
+    let mut card = gen_card(String::from("Visa"), String::from("Classic"), 1, "alice").unwrap();
+    storage.save_accounts(&HashMap::from([(1, card.clone())])).unwrap();
+    let stale = fs::read_to_string(&storage.account_path).unwrap();
+
+    // a crash after the journal was written but before the data files were replaced
+    card.balance = Decimal::from(50);
+    let accounts = serde_json::to_string_pretty(&HashMap::from([(1, &card)])).unwrap();
+    let trades = String::from(r#"{"1": {"timestamp": 10, "transaction_type": {"action": "credit", "amount": 50.0}, "target_user": "shop"}}"#);
+    let journal = HashMap::from([(storage.account_path.clone(), accounts.clone()), (storage.trade_path.clone(), trades.clone())]);
+    fs::write(&storage.journal_path, serde_json::to_string(&journal).unwrap()).unwrap();
+    assert_eq!(fs::read_to_string(&storage.account_path).unwrap(), stale);
+
+    storage.recover().unwrap();
+
+    assert_eq!(fs::read_to_string(&storage.account_path).unwrap(), accounts);
+    assert_eq!(fs::read_to_string(&storage.trade_path).unwrap(), trades);
+    assert_eq!(json(&storage.load_accounts().unwrap()[&1]), json(&card));
+    assert_eq!(storage.load_trades().unwrap().len(), 1);
+    assert!(!Path::new(&storage.journal_path).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recover_discards_a_journal_that_never_finished_writing() {
+    let dir = temp_dir("journal-partial");
+    let storage = JsonStorage::in_dir(&dir);
+    fs::write(&storage.account_path, "{}").unwrap();
+
+    let card = gen_card(String::from("Visa"), String::from("Classic"), 1, "alice").unwrap();
+    storage.save_accounts(&HashMap::from([(1, card)])).unwrap();
+    let stored = fs::read_to_string(&storage.account_path).unwrap();
+    fs::write(&storage.journal_path, format!(r#"{{"{}": "{{\"1\": "#, storage.account_path)).unwrap();
+
+    storage.recover().unwrap();
+
+    assert_eq!(fs::read_to_string(&storage.account_path).unwrap(), stored);
+    assert!(!Path::new(&storage.journal_path).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
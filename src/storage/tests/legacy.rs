@@ -0,0 +1,54 @@
+mod common;
+
+use std::fs;
+use rusqlite::{params, Connection};
+use structure::Side;
+use storage::{JsonStorage, SqliteStorage, Storage};
+use common::temp_dir;
+
+#[test]
+fn json_holds_with_a_lowercase_buy_type_still_load() {
+    let dir = temp_dir("legacy-json");
+    let storage = JsonStorage::in_dir(&dir);
+    fs::write(&storage.stockhold_path, r#"{
+        "alice": [{"timestamp": 10, "stock": {"buy_type": "long", "symbol": "AAPL", "hand": "1", "leverage": "100", "price": "100"}}],
+        "bob": [{"timestamp": 20, "stock": {"buy_type": "short", "symbol": "TSLA", "hand": "2", "leverage": "100", "price": "200"}}]
+    }"#).unwrap();
+
+    let holdings = storage.load_holdings().unwrap();
+    assert_eq!(holdings["alice"][0].stock.buy_type, Side::Long);
+    assert_eq!(holdings["bob"][0].stock.buy_type, Side::Short);
+
+    // the next save writes them back in the typed spelling
+    storage.save_holdings(&holdings).unwrap();
+    let written = fs::read_to_string(&storage.stockhold_path).unwrap();
+    assert!(written.contains("\"Long\"") && !written.contains("\"long\""));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sqlite_holds_with_a_lowercase_buy_type_still_load() {
+    let dir = temp_dir("legacy-sqlite");
+    let path = dir.join("bank.db").to_string_lossy().into_owned();
+    let storage = SqliteStorage::open(&path).unwrap();
+    let conn = Connection::open(&path).unwrap();
+    conn.execute(
+        "INSERT INTO stock_holds (card_holder, position, timestamp, buy_type, symbol, hand, leverage, price) VALUES (?1, 0, 10, 'long', 'AAPL', '1', '100', '100')",
+        params!["alice"],
+    ).unwrap();
+
+    assert_eq!(storage.load_holdings().unwrap()["alice"][0].stock.buy_type, Side::Long);
+    assert_eq!(storage.load_holdings_of("alice").unwrap()["alice"][0].stock.buy_type, Side::Long);
+
+    drop(conn);
+    drop(storage);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn requests_still_refuse_a_lowercase_buy_type() {
+    assert!("long".parse::<Side>().is_err());
+    assert_eq!(Side::from_stored("long"), Ok(Side::Long));
+    assert!(Side::from_stored("lng").is_err());
+}
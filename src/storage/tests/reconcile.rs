@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use rust_decimal::Decimal;
+use structure::{CardInfo, Discrepancy, Side, Stock, StockHold, TradeHistory, TransactionType};
+use function::gen_card;
+use storage::{open_ledger, reconcile_storage, MemoryStorage};
+
+fn card(card_holder: &str, balance: i64) -> CardInfo {
+    let mut card = gen_card(String::from("Visa"), String::from("Classic"), 1, card_holder).unwrap();
+    card.balance = Decimal::from(balance);
+    card
+}
+
+fn credit(timestamp: i64, amount: f64) -> TradeHistory {
+    TradeHistory {
+        timestamp,
+        transaction_type: TransactionType::Credit { amount },
+        target_user: String::from("shop"),
+        linked_trade: None,
+        reason: None,
+    }
+}
+
+fn discrepancies(storage: &MemoryStorage) -> Vec<Discrepancy> {
+    reconcile_storage(storage, 0).unwrap().discrepancies
+}
+
+#[test]
+fn balance_no_trade_adds_up_to_is_a_balance_mismatch() {
+    let storage = MemoryStorage::new(HashMap::from([(1, card("alice", 50))]), HashMap::new(), HashMap::new());
+    // the ledger opens at the stored balance, so only the trade replay disagrees
+    open_ledger(&storage, 0).unwrap();
+
+    let found = discrepancies(&storage);
+    assert_eq!(found.len(), 1, "{:?}", found);
+    assert!(matches!(&found[0], Discrepancy::BalanceMismatch { card_holder, stored, replayed }
+        if card_holder == "alice" && *stored == Decimal::from(50) && replayed.is_zero()));
+}
+
+#[test]
+fn indexed_trade_that_is_gone_is_a_missing_trade() {
+    let mut alice = card("alice", 0);
+    alice.transaction.get_or_insert_default().push(1000, 7);
+    let storage = MemoryStorage::new(HashMap::from([(1, alice)]), HashMap::new(), HashMap::new());
+
+    let found = discrepancies(&storage);
+    assert_eq!(found.len(), 1, "{:?}", found);
+    assert!(matches!(&found[0], Discrepancy::MissingTrade { card_holder, timestamp: 1000, trade_id: 7 } if card_holder == "alice"));
+}
+
+#[test]
+fn trade_no_card_indexes_is_an_orphaned_trade() {
+    let storage = MemoryStorage::new(HashMap::from([(1, card("alice", 0))]), HashMap::from([(9, credit(1000, 5.0))]), HashMap::new());
+
+    let found = discrepancies(&storage);
+    assert_eq!(found.len(), 1, "{:?}", found);
+    assert!(matches!(&found[0], Discrepancy::OrphanedTrade { trade_id: 9, timestamp: 1000, card_holder: None }));
+}
+
+#[test]
+fn hold_without_a_card_is_a_dangling_hold() {
+    let hold = StockHold {
+        timestamp: 1000,
+        stock: Stock {
+            buy_type: Side::Long,
+            symbol: String::from("AAPL"),
+            hand: Decimal::ONE,
+            leverage: Decimal::ONE_HUNDRED,
+            price: Decimal::ONE_HUNDRED,
+        },
+        stop_loss: None,
+        take_profit: None,
+    };
+    let storage = MemoryStorage::new(HashMap::from([(1, card("alice", 0))]), HashMap::new(), HashMap::from([(String::from("bob"), vec![hold])]));
+
+    let found = discrepancies(&storage);
+    assert_eq!(found.len(), 1, "{:?}", found);
+    assert!(matches!(&found[0], Discrepancy::DanglingHold { card_holder, symbol, timestamp: 1000, reason }
+        if card_holder == "bob" && symbol == "AAPL" && reason == "No card for this card holder"));
+}
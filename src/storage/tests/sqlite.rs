@@ -0,0 +1,209 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use rust_decimal::Decimal;
+use rusqlite::{params, Connection};
+use structure::{ClosedPosition, IdempotentResponse, LedgerEntry, LimitOrder, OrderSide, OrderStatus, Posting, Side, Stock, StockHold, TargetInfo, TradeHistory, TradeIndex, TransactionType};
+use function::gen_card;
+use storage::{import_storage, Changes, MemoryStorage, SqliteStorage, Storage};
+use common::{json, temp_dir};
+
+fn hold(timestamp: i64, symbol: &str) -> StockHold {
+    StockHold {
+        timestamp,
+        stock: Stock {
+            buy_type: Side::Short,
+            symbol: symbol.to_string(),
+            hand: Decimal::new(15, 1),
+            leverage: Decimal::from(200),
+            price: Decimal::new(10025, 2),
+        },
+        stop_loss: Some(Decimal::from(110)),
+        take_profit: None,
+    }
+}
+
+fn closed(trade_id: i64) -> ClosedPosition {
+    ClosedPosition {
+        symbol: String::from("AAPL"),
+        buy_type: Side::Long,
+        hand: Decimal::ONE,
+        leverage: Decimal::from(100),
+        entry_price: Decimal::from(100),
+        exit_price: Decimal::from(90),
+        opened_at: 10,
+        closed_at: 20,
+        earning: Decimal::from(-10),
+        reason: Some(String::from("stop_loss")),
+        trade_id,
+    }
+}
+
+#[test]
+fn every_kind_of_row_reads_back_as_written() {
+    let storage = SqliteStorage::open_in_memory().unwrap();
+
+    // account.json used to map each second to a trade id, the index keeps that order
+    let mut legacy = gen_card(String::from("Visa"), String::from("Classic"), 1, "alice").unwrap();
+    legacy.transaction = Some(serde_json::from_str::<TradeIndex>(r#"{"20": 2, "10": 1}"#).unwrap());
+    legacy.connection = Some(HashMap::from([(String::from("discord"), vec![TargetInfo { target: String::from("discord"), token: String::from("t1") }])]));
+    legacy.leaderboard_opt_out = true;
+    let mut listed = gen_card(String::from("MasterCard"), String::from("Platinum"), 2, "bob").unwrap();
+    let mut index = TradeIndex::default();
+    index.push(30, 3);
+    index.push(30, 4);
+    listed.transaction = Some(index);
+    listed.balance = Decimal::new(12345, 2);
+    let accounts = HashMap::from([(1, legacy), (2, listed)]);
+
+    let trades = HashMap::from([
+        (1, TradeHistory { timestamp: 10, transaction_type: TransactionType::Credit { amount: 1.5 }, target_user: String::from("shop"), linked_trade: None, reason: None }),
+        (3, TradeHistory { timestamp: 30, transaction_type: TransactionType::Debit { amount: 2.0 }, target_user: String::from("alice"), linked_trade: Some(4), reason: Some(String::from("stop_loss")) }),
+    ]);
+    let holdings = HashMap::from([(String::from("alice"), vec![hold(10, "AAPL"), hold(20, "TSLA")])]);
+    let orders = HashMap::from([(7, LimitOrder {
+        card_holder: String::from("alice"),
+        side: OrderSide::Buy,
+        buy_type: Side::Long,
+        symbol: String::from("AAPL"),
+        hand: Decimal::ONE,
+        leverage: Decimal::from(100),
+        limit_price: Decimal::from(95),
+        reserved: Decimal::from(95),
+        hold_timestamp: None,
+        created_at: 10,
+        status: OrderStatus::Open,
+        filled_price: None,
+        closed_at: None,
+    })]);
+    let closed_map = HashMap::from([(String::from("alice"), vec![closed(3)])]);
+    let ledger = HashMap::from([(1, LedgerEntry {
+        timestamp: 10,
+        trade_id: Some(1),
+        memo: String::from("deposit"),
+        postings: vec![Posting::new("card:alice", Decimal::new(15, 1)), Posting::new("treasury", Decimal::new(-15, 1))],
+    })]);
+
+    let responses = HashMap::from([(String::from("dc_trade:alice:k1"), IdempotentResponse {
+        status: 200,
+        content_type: Some(String::from("text/plain; charset=utf-8")),
+        body: String::from("ok"),
+        stored_at: 10,
+    })]);
+
+    storage.save_changes(Changes {
+        accounts: Some(&accounts),
+        trades: Some(&trades),
+        holdings: Some(&holdings),
+        orders: Some(&orders),
+        closed: Some(&closed_map),
+        ledger: Some(&ledger),
+        responses: Some(&responses),
+    }).unwrap();
+
+    assert_eq!(json(&storage.load_accounts().unwrap()), json(&accounts));
+    assert_eq!(json(&storage.load_trades().unwrap()), json(&trades));
+    assert_eq!(json(&storage.load_holdings().unwrap()), json(&holdings));
+    assert_eq!(json(&storage.load_orders().unwrap()), json(&orders));
+    assert_eq!(json(&storage.load_closed().unwrap()), json(&closed_map));
+    assert_eq!(json(&storage.load_ledger().unwrap()), json(&ledger));
+    assert_eq!(json(&storage.load_response("dc_trade:alice:k1").unwrap()), json(&responses.get("dc_trade:alice:k1")));
+    assert_eq!(storage.prune_responses(11), Ok(1));
+    assert!(storage.load_response("dc_trade:alice:k1").unwrap().is_none());
+
+    let alice = storage.load_accounts_of(&["alice"]).unwrap();
+    let seqs: Vec<(u64, i64)> = alice[&1].transaction.iter().flat_map(|index| index.iter()).map(|t| (t.seq, t.trade_id)).collect();
+    assert_eq!(seqs, vec![(1, 1), (2, 2)]);
+    assert_eq!(json(&storage.load_accounts_of(&[&accounts[&2].card_number]).unwrap()), json(&HashMap::from([(2, &accounts[&2])])));
+    assert_eq!(json(&storage.load_trades_of(&[3, 99]).unwrap()), json(&HashMap::from([(3, &trades[&3])])));
+    assert_eq!(storage.load_open_orders().unwrap().len(), 1);
+
+    // closed positions are appended, holdings replaced
+    storage.save_changes(Changes {
+        holdings: Some(&HashMap::from([(String::from("alice"), vec![hold(20, "TSLA")])])),
+        closed: Some(&HashMap::from([(String::from("alice"), vec![closed(4)])])),
+        ..Changes::default()
+    }).unwrap();
+    let trade_ids: Vec<i64> = storage.load_closed_of("alice").unwrap()["alice"].iter().map(|c| c.trade_id).collect();
+    assert_eq!(trade_ids, vec![3, 4]);
+    assert_eq!(storage.load_holdings_of("alice").unwrap()["alice"].len(), 1);
+}
+
+#[test]
+fn migration_8_numbers_the_trades_of_a_version_7_database() {
+    let dir = temp_dir("migration-8");
+    let path = dir.join("bank.db").to_string_lossy().into_owned();
+    {
+        // the tables that later migrations touch, as they were at version 7
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE cards (
+                id INTEGER PRIMARY KEY,
+                card_holder TEXT NOT NULL,
+                card_number TEXT NOT NULL,
+                good_thru TEXT NOT NULL,
+                verify_number TEXT NOT NULL,
+                scheme TEXT NOT NULL,
+                card_type TEXT NOT NULL,
+                balance TEXT NOT NULL,
+                leaderboard_opt_out INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE connections (
+                card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+                platform TEXT NOT NULL,
+                target TEXT NOT NULL,
+                token TEXT NOT NULL
+            );
+            CREATE TABLE card_trades (
+                card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+                timestamp INTEGER NOT NULL,
+                trade_id INTEGER NOT NULL,
+                PRIMARY KEY (card_id, timestamp)
+            );
+            CREATE TABLE orders (id INTEGER PRIMARY KEY, card_holder TEXT NOT NULL);
+            PRAGMA user_version = 7;",
+        ).unwrap();
+        conn.execute(
+            "INSERT INTO cards (id, card_holder, card_number, good_thru, verify_number, scheme, card_type, balance) VALUES (1, 'alice', '4000', '12/30', '123', 'Visa', 'Classic', '10')",
+            params![],
+        ).unwrap();
+        for (timestamp, trade_id) in [(30, 5), (10, 9), (20, 7)] {
+            conn.execute("INSERT INTO card_trades (card_id, timestamp, trade_id) VALUES (1, ?1, ?2)", params![timestamp, trade_id]).unwrap();
+        }
+    }
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(storage.schema_version().unwrap(), 11);
+    let accounts = storage.load_accounts().unwrap();
+    let index: Vec<(u64, i64, i64)> = accounts[&1].transaction.iter().flat_map(|index| index.iter()).map(|t| (t.seq, t.timestamp, t.trade_id)).collect();
+    assert_eq!(index, vec![(1, 10, 9), (2, 20, 7), (3, 30, 5)]);
+
+    // the next trade of the second it shares with another one is indexed too
+    let mut card = accounts[&1].clone();
+    card.transaction.as_mut().unwrap().push(30, 6);
+    storage.save_accounts(&HashMap::from([(1, card)])).unwrap();
+    assert_eq!(storage.load_accounts().unwrap()[&1].transaction.iter().flat_map(|index| index.iter()).count(), 4);
+
+    drop(storage);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn import_copies_everything_and_refuses_a_target_with_cards() {
+    let card = gen_card(String::from("Visa"), String::from("Classic"), 1, "alice").unwrap();
+    let trades = HashMap::from([(1, TradeHistory { timestamp: 10, transaction_type: TransactionType::Credit { amount: 1.0 }, target_user: String::from("shop"), linked_trade: None, reason: None })]);
+    let holdings = HashMap::from([(String::from("alice"), vec![hold(10, "AAPL")])]);
+    let from = MemoryStorage::new(HashMap::from([(1, card)]), trades, holdings);
+    from.save_closed(&HashMap::from([(String::from("alice"), vec![closed(1)])])).unwrap();
+    from.save_sequence("trade", 1).unwrap();
+
+    let to = SqliteStorage::open_in_memory().unwrap();
+    assert_eq!(import_storage(&from, &to), Ok((1, 1, 1, 0, 1, 0)));
+    assert_eq!(json(&to.load_accounts().unwrap()), json(&from.load_accounts().unwrap()));
+    assert_eq!(to.load_sequence("trade"), Ok(1));
+
+    // a second import would append every closed position again
+    assert!(import_storage(&from, &to).unwrap_err().contains("already has cards"));
+    assert_eq!(to.load_closed().unwrap()["alice"].len(), 1);
+}
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use rust_decimal::Decimal;
-use serde::{Serialize, Deserialize};
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Serialize, Deserialize, Deserializer};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CardInfo {
     pub card_holder: String,
     pub card_number: String,
@@ -12,7 +15,86 @@ pub struct CardInfo {
     pub card_type: String,
     pub balance: Decimal,
     pub connection: Option<HashMap<String, Vec<TargetInfo>>>,
-    pub transaction: Option<HashMap<i64, i64>>,
+    pub transaction: Option<TradeIndex>,
+    // hidden from /leaderboard
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub leaderboard_opt_out: bool,
+}
+
+// one trade in a card's history, `seq` counts up from 1 for each card
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TradeRef {
+    pub seq: u64,
+    pub timestamp: i64,
+    pub trade_id: i64,
+}
+
+// a card's trades in the order they were made, any number of them may share a second
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(try_from = "TradeIndexFormat", into = "Vec<TradeRef>")]
+pub struct TradeIndex(Vec<TradeRef>);
+
+// account.json used to map each second to a single trade id
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TradeIndexFormat {
+    List(Vec<TradeRef>),
+    Legacy(HashMap<String, i64>),
+}
+
+impl TradeIndex {
+    // appends a trade after the last one, returning its sequence number
+    pub fn push(&mut self, timestamp: i64, trade_id: i64) -> u64 {
+        let seq = self.0.last().map_or(1, |last| last.seq + 1);
+        self.0.push(TradeRef { seq, timestamp, trade_id });
+        seq
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, TradeRef> {
+        self.0.iter()
+    }
+
+    // the first trade indexed at this second
+    pub fn first_at(&self, timestamp: i64) -> Option<i64> {
+        self.0.iter().find(|r| r.timestamp == timestamp).map(|r| r.trade_id)
+    }
+}
+
+impl From<Vec<TradeRef>> for TradeIndex {
+    fn from(mut refs: Vec<TradeRef>) -> Self {
+        refs.sort_by_key(|r| r.seq);
+        TradeIndex(refs)
+    }
+}
+
+impl From<TradeIndex> for Vec<TradeRef> {
+    fn from(index: TradeIndex) -> Self {
+        index.0
+    }
+}
+
+impl TryFrom<TradeIndexFormat> for TradeIndex {
+    type Error = String;
+
+    fn try_from(format: TradeIndexFormat) -> Result<Self, Self::Error> {
+        let refs = match format {
+            TradeIndexFormat::List(refs) => refs,
+            TradeIndexFormat::Legacy(map) => {
+                let mut trades = Vec::with_capacity(map.len());
+                for (timestamp, trade_id) in map {
+                    let timestamp = timestamp.parse::<i64>()
+                        .map_err(|_| format!("Invalid trade timestamp {}", timestamp))?;
+                    trades.push((timestamp, trade_id));
+                }
+                trades.sort();
+                trades.into_iter()
+                    .zip(1..)
+                    .map(|((timestamp, trade_id), seq)| TradeRef { seq, timestamp, trade_id })
+                    .collect()
+            }
+        };
+        Ok(TradeIndex::from(refs))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -27,6 +109,45 @@ pub struct TradeHistory {
     pub timestamp: i64,
     pub transaction_type: TransactionType,
     pub target_user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linked_trade: Option<i64>,
+    // why the bot made this trade, e.g. "stop_loss" or "take_profit"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// one line of a ledger entry, `amount` is added to the account's balance
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Posting {
+    pub account: String,
+    pub amount: Decimal,
+}
+
+impl Posting {
+    pub fn new(account: &str, amount: Decimal) -> Self {
+        Posting { account: account.to_string(), amount }
+    }
+}
+
+// a double-entry ledger entry, its postings always sum to zero
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LedgerEntry {
+    pub timestamp: i64,
+    // the trade history record this entry backs, None for opening balances
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trade_id: Option<i64>,
+    pub memo: String,
+    pub postings: Vec<Posting>,
+}
+
+// the response to a request carrying an idempotency key, stored with the changes it made
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdempotentResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub body: String,
+    pub stored_at: i64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -34,6 +155,21 @@ pub struct DiscordTrade {
     pub card_holder: String,
     pub target_user: String,
     pub transaction_type: TransactionType,
+    pub token: String,
+    pub target: String,
+    // retries carrying the same key get the first response back instead of running again
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Transfer {
+    pub card_holder: String,
+    // card holder or card number of the receiving card
+    pub recipient: String,
+    pub amount: f64,
+    pub token: String,
+    pub target: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -48,13 +184,72 @@ pub struct Identification {
     pub card_holder: String,
 }
 
+// card holder plus the platform token issued by /connect
+#[derive(Serialize, Deserialize)]
+pub struct AccountAuth {
+    pub card_holder: String,
+    pub token: String,
+    pub target: String,
+}
+
+// /connect, the card details returned by /signup prove the caller owns the card
 #[derive(Serialize, Deserialize)]
 pub struct TargetVerify {
     pub card_holder: String,
     pub target: String,
+    pub card_number: String,
+    pub good_thru: String,
+    pub verify_number: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeConnection {
+    pub card_holder: String,
+    pub token: String,
+    pub target: String,
+    // platform whose connection is removed, may differ from `target`
+    pub platform: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RetireKey {
+    pub admin_token: String,
+    pub key_id: String,
 }
 
 #[derive(Serialize, Deserialize)]
+pub struct AdminRequest {
+    pub admin_token: String,
+}
+
+// one problem found by reconciliation, `kind` tells them apart in the report
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    // replaying the card's trades doesn't give its stored balance
+    BalanceMismatch { card_holder: String, stored: Decimal, replayed: Decimal },
+    // the card's ledger account doesn't give its stored balance
+    LedgerMismatch { card_holder: String, stored: Decimal, ledger: Decimal },
+    // the card's trade index points at a trade that doesn't exist
+    MissingTrade { card_holder: String, timestamp: i64, trade_id: i64 },
+    // no card's trade index points at this trade
+    OrphanedTrade { trade_id: i64, timestamp: i64, card_holder: Option<String> },
+    // two trades of the card in the same second, the index only kept one
+    DuplicateTimestamp { card_holder: String, timestamp: i64, kept: i64, lost: i64 },
+    // a held stock that can't be sold as it is
+    DanglingHold { card_holder: String, symbol: String, timestamp: i64, reason: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReconciliationReport {
+    pub checked_at: i64,
+    pub cards: usize,
+    pub trades: usize,
+    pub holds: usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TargetInfo {
     pub target: String,
     pub token: String,
@@ -65,9 +260,127 @@ pub struct Symbol {
     pub symbol: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Long,
+    Short,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Side::Long => write!(f, "Long"),
+            Side::Short => write!(f, "Short"),
+        }
+    }
+}
+
+impl FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Long" => Ok(Side::Long),
+            "Short" => Ok(Side::Short),
+            other => Err(format!("Unknown buy type {}, use Long or Short", other)),
+        }
+    }
+}
+
+impl Side {
+    // holdings, orders and closed positions stored before buy_type was typed may spell it in lowercase
+    pub fn from_stored(s: &str) -> Result<Self, String> {
+        match s {
+            "long" => Ok(Side::Long),
+            "short" => Ok(Side::Short),
+            other => Side::from_str(other),
+        }
+    }
+}
+
+// requests only take Long and Short, stored rows also `Side::from_stored`
+fn stored_side<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Side, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Side::from_stored(&s).map_err(serde::de::Error::custom)
+}
+
+// number of hands to buy, always positive
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Hand(Decimal);
+
+impl Hand {
+    pub fn get(self) -> Decimal {
+        self.0
+    }
+}
+
+impl TryFrom<Decimal> for Hand {
+    type Error = String;
+
+    fn try_from(hand: Decimal) -> Result<Self, Self::Error> {
+        if hand <= Decimal::ZERO {
+            return Err(String::from("Hand must be positive"));
+        }
+        Ok(Hand(hand))
+    }
+}
+
+impl From<Hand> for Decimal {
+    fn from(hand: Hand) -> Self {
+        hand.0
+    }
+}
+
+// leverage in percent, a whole number of at least 1; the upper limit depends on the card
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Leverage(i64);
+
+impl Leverage {
+    pub fn percent(self) -> i64 {
+        self.0
+    }
+
+    pub fn get(self) -> Decimal {
+        Decimal::from(self.0)
+    }
+}
+
+impl TryFrom<Decimal> for Leverage {
+    type Error = String;
+
+    fn try_from(leverage: Decimal) -> Result<Self, Self::Error> {
+        match leverage.to_i64() {
+            Some(percent) if percent >= 1 && leverage.fract().is_zero() => Ok(Leverage(percent)),
+            _ => Err(format!("Leverage must be a whole number of at least 1, got {}", leverage)),
+        }
+    }
+}
+
+impl From<Leverage> for Decimal {
+    fn from(leverage: Leverage) -> Self {
+        leverage.get()
+    }
+}
+
+// body of a 422 response, `field` names the part of the request that was refused
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub error: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, error: impl Into<String>) -> Self {
+        FieldError { field: field.into(), error: error.into() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Stock {
-    pub buy_type: String,
+    #[serde(deserialize_with = "stored_side")]
+    pub buy_type: Side,
     pub symbol: String,
     pub hand: Decimal,
     pub leverage: Decimal,
@@ -76,28 +389,273 @@ pub struct Stock {
 
 #[derive(Serialize, Deserialize)]
 pub struct BuyStock {
-    pub buy_type: String,
+    pub buy_type: Side,
     pub symbol: String,
-    pub hand: Decimal,
-    pub leverage: Decimal,
+    pub hand: Hand,
+    pub leverage: Leverage,
+    #[serde(default)]
+    pub stop_loss: Option<Decimal>,
+    #[serde(default)]
+    pub take_profit: Option<Decimal>,
     pub token: String,
     pub target: String,
     pub card_holder: String,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SellStock {
     pub symbol: String,
-    pub timestamp: i64,
+    // sells the position bought at this time; left out, `hand` is sold from the oldest positions first
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    // how many hands to sell, the whole position when left out
+    #[serde(default)]
+    pub hand: Option<Decimal>,
     pub token: String,
     pub target: String,
     pub card_holder: String,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct StockHold {
     pub timestamp: i64,
     pub stock: Stock,
+    // the position is sold automatically once the price reaches either of these
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_loss: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub take_profit: Option<Decimal>,
+}
+
+// a sold position, kept for realized profit and statistics
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClosedPosition {
+    pub symbol: String,
+    #[serde(deserialize_with = "stored_side")]
+    pub buy_type: Side,
+    pub hand: Decimal,
+    pub leverage: Decimal,
+    pub entry_price: Decimal,
+    pub exit_price: Decimal,
+    pub opened_at: i64,
+    pub closed_at: i64,
+    // what the card actually gained or lost, after the zero balance floor
+    pub earning: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // the credit or debit written for the sale
+    pub trade_id: i64,
+}
+
+// replaces both triggers of a held stock, a missing one is cleared
+#[derive(Serialize, Deserialize)]
+pub struct SetTriggers {
+    pub symbol: String,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub stop_loss: Option<Decimal>,
+    #[serde(default)]
+    pub take_profit: Option<Decimal>,
+    pub token: String,
+    pub target: String,
+    pub card_holder: String,
+}
+
+// Buy opens a position once the price reaches `limit_price`, Sell closes the
+// position bought at `hold_timestamp` once the price reaches it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+impl fmt::Display for OrderSide {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderSide::Buy => write!(f, "Buy"),
+            OrderSide::Sell => write!(f, "Sell"),
+        }
+    }
+}
+
+impl FromStr for OrderSide {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Buy" => Ok(OrderSide::Buy),
+            "Sell" => Ok(OrderSide::Sell),
+            other => Err(format!("Unknown order side {}, use Buy or Sell", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OrderStatus {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderStatus::Open => write!(f, "Open"),
+            OrderStatus::Filled => write!(f, "Filled"),
+            OrderStatus::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Open" => Ok(OrderStatus::Open),
+            "Filled" => Ok(OrderStatus::Filled),
+            "Cancelled" => Ok(OrderStatus::Cancelled),
+            other => Err(format!("Unknown order status {}", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LimitOrder {
+    pub card_holder: String,
+    pub side: OrderSide,
+    #[serde(deserialize_with = "stored_side")]
+    pub buy_type: Side,
+    pub symbol: String,
+    pub hand: Decimal,
+    pub leverage: Decimal,
+    pub limit_price: Decimal,
+    // taken from the balance when a buy order is placed, returned on cancel
+    pub reserved: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_timestamp: Option<i64>,
+    pub created_at: i64,
+    pub status: OrderStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filled_price: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlaceOrder {
+    pub side: OrderSide,
+    pub symbol: String,
+    pub limit_price: Decimal,
+    // buy orders only
+    #[serde(default)]
+    pub buy_type: Option<Side>,
+    #[serde(default)]
+    pub hand: Option<Hand>,
+    #[serde(default)]
+    pub leverage: Option<Leverage>,
+    // sell orders only, timestamp of the held position as in /sell_stock
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+    pub token: String,
+    pub target: String,
+    pub card_holder: String,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelOrder {
+    pub order_id: i64,
+    pub token: String,
+    pub target: String,
+    pub card_holder: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Leaderboard {
+    // "equity", "realized_pnl" or "return"
+    pub metric: String,
+    // "day", "week" or "all", equity is always the current value
+    #[serde(default = "default_period")]
+    pub period: String,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+fn default_period() -> String {
+    String::from("all")
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaderboardOptOut {
+    pub opt_out: bool,
+    pub token: String,
+    pub target: String,
+    pub card_holder: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TradeAction {
+    Credit,
+    Debit,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TradeSort {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+// /check_trade, every filter is optional
+#[derive(Serialize, Deserialize)]
+pub struct TradeHistoryQuery {
+    pub card_holder: String,
+    pub token: String,
+    pub target: String,
+    // unix seconds, `from` inclusive and `to` exclusive
+    #[serde(default)]
+    pub from: Option<i64>,
+    #[serde(default)]
+    pub to: Option<i64>,
+    #[serde(default)]
+    pub action: Option<TradeAction>,
+    // matches `target_user` exactly
+    #[serde(default)]
+    pub counterparty: Option<String>,
+    #[serde(default)]
+    pub min_amount: Option<Decimal>,
+    #[serde(default)]
+    pub max_amount: Option<Decimal>,
+    #[serde(default)]
+    pub sort: TradeSort,
+    // `next_cursor` of the previous page
+    #[serde(default)]
+    pub cursor: Option<u64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct TradeEntry {
+    pub id: i64,
+    pub seq: u64,
+    #[serde(flatten)]
+    pub trade: TradeHistory,
+}
+
+#[derive(Serialize)]
+pub struct TradePage {
+    pub trades: Vec<TradeEntry>,
+    // None on the last page
+    pub next_cursor: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -106,3 +664,30 @@ pub struct StockHistory {
     pub period: String,
     pub interval: String,
 }
+
+// request bodies that carry connection credentials
+pub trait Credentials {
+    fn card_holder(&self) -> &str;
+    fn target(&self) -> &str;
+    fn token(&self) -> &str;
+}
+
+macro_rules! impl_credentials {
+    ($($t:ty),*) => {
+        $(impl Credentials for $t {
+            fn card_holder(&self) -> &str {
+                &self.card_holder
+            }
+
+            fn target(&self) -> &str {
+                &self.target
+            }
+
+            fn token(&self) -> &str {
+                &self.token
+            }
+        })*
+    };
+}
+
+impl_credentials!(AccountAuth, DiscordTrade, Transfer, BuyStock, SellStock, RevokeConnection, PlaceOrder, CancelOrder, SetTriggers, LeaderboardOptOut, TradeHistoryQuery);